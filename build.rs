@@ -1,3 +1,7 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
 fn main() {
     cc::Build::new()
         .cpp(true)
@@ -5,4 +9,32 @@ fn main() {
         .compile("llvm-bindings");
     println!("cargo:rustc-link-lib=LLVM-12");
     println!("cargo:rerun-if-changed=src/llvm-bindings.cpp");
+
+    build_runtime();
+}
+
+// the builtins, as an archive that executables compiled ahead of time are linked against
+fn build_runtime() {
+    let rustc = env::var("RUSTC").unwrap();
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let archive = Path::new(&out_dir).join("libkaleidoscope_runtime.a");
+
+    let status = Command::new(rustc)
+        .args(&[
+            "--crate-type",
+            "staticlib",
+            "--crate-name",
+            "kaleidoscope_runtime",
+        ])
+        .args(&["--edition", "2018", "--cfg", "runtime_archive"])
+        .args(&["-C", "opt-level=2", "-C", "panic=abort", "-C", "lto"])
+        .arg("--target")
+        .arg(env::var("TARGET").unwrap())
+        .arg("-o")
+        .arg(&archive)
+        .arg("src/runtime.rs")
+        .status()
+        .expect("couldn't run rustc");
+    assert!(status.success(), "couldn't build the runtime archive");
+    println!("cargo:rerun-if-changed=src/runtime.rs");
 }
@@ -1,282 +1,1287 @@
-use std::collections::HashMap;
-use std::ffi::CString;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
 
-use crate::lexer::Token;
+use crate::diagnostics::Diagnostic;
+use crate::lexer::{Span, Token};
+use crate::llvm::{
+    Builder, Context, DebugInfo, FloatPredicate, Function, FunctionPassManager, Jit, Module,
+    ResourceTracker, TargetMachine, Value,
+};
 use crate::parser::{
-    BinaryExprAstNode, CallExprAstNode, ExprAstNode, FunctionAstNode, NumberExprAstNode,
-    PrototypeAstNode, VariableExprAstNode,
+    BinaryExprAstNode, CallExprAstNode, ExprAstNode, ForExprAstNode, FunctionAstNode,
+    IfExprAstNode, NumberExprAstNode, PrototypeAstNode, PrototypeKind, UnaryExprAstNode,
+    VarExprAstNode, VariableExprAstNode,
 };
+use crate::runtime;
 
-mod llvm {
-    extern "C" {
-        pub type Value;
-        pub type LlvmContext;
-        pub type IrBuilder;
-        pub type Module;
-        pub type Function;
-
-        pub fn get_context() -> *mut LlvmContext;
-        pub fn get_builder(context: *mut LlvmContext) -> *mut IrBuilder;
-        pub fn get_module(context: *mut LlvmContext) -> *mut Module;
-        pub fn get_constant_fp(context: *mut LlvmContext, value: f64) -> *mut Value;
-        pub fn print_value(value: *mut Value);
-        pub fn print_function(function: *mut Function);
-        pub fn builder_create_f_add(
-            builder: *mut IrBuilder,
-            lhs: *mut Value,
-            rhs: *mut Value,
-            op: *const i8,
-        ) -> *mut Value;
-        pub fn builder_create_f_sub(
-            builder: *mut IrBuilder,
-            lhs: *mut Value,
-            rhs: *mut Value,
-            op: *const i8,
-        ) -> *mut Value;
-        pub fn builder_create_f_mul(
-            builder: *mut IrBuilder,
-            lhs: *mut Value,
-            rhs: *mut Value,
-            op: *const i8,
-        ) -> *mut Value;
-        pub fn builder_create_f_cmp_lt(
-            context: *mut LlvmContext,
-            builder: *mut IrBuilder,
-            lhs: *mut Value,
-            rhs: *mut Value,
-            op: *const i8,
-        ) -> *mut Value;
-        pub fn module_get_function(module: *mut Module, name: *const i8) -> *mut Function;
-        pub fn builder_create_call(
-            builder: *mut IrBuilder,
-            function: *mut Function,
-            arg_buf: *mut *mut Value,
-            arg_size: usize,
-            name: *const i8,
-        ) -> *mut Value;
-        pub fn module_create_function(
-            context: *mut LlvmContext,
-            module: *mut Module,
-            name: *const i8,
-            args: *mut *const i8,
-            args_size: usize,
-        ) -> *mut Function;
-        pub fn create_function_body(
-            context: *mut LlvmContext,
-            function: *mut Function,
-            builder: *mut IrBuilder,
-        );
-        pub fn get_function_args(function: *mut Function) -> *mut *mut Value;
-        pub fn builder_create_ret(builder: *mut IrBuilder, value: *mut Value);
+/// How much optimization to do, as with the `-O` flags of a C compiler.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OptLevel {
+    O0,
+    O1,
+    O2,
+    O3,
+    /// Like `O2`, but inlining less to keep code small.
+    Os,
+}
+
+impl OptLevel {
+    fn level(self) -> u32 {
+        match self {
+            OptLevel::O0 => 0,
+            OptLevel::O1 => 1,
+            OptLevel::O2 | OptLevel::Os => 2,
+            OptLevel::O3 => 3,
+        }
+    }
+
+    fn size_level(self) -> u32 {
+        match self {
+            OptLevel::Os => 1,
+            _ => 0,
+        }
     }
 }
 
-struct Module {
-    inner: *mut llvm::Module,
+impl Default for OptLevel {
+    fn default() -> Self {
+        OptLevel::O1
+    }
 }
 
-impl Module {
-    fn get_function(&mut self, name: &str) -> *mut llvm::Function {
-        unsafe {
-            let s = CString::new(name).unwrap();
-            llvm::module_get_function(self.inner, s.as_ptr())
+impl fmt::Display for OptLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptLevel::Os => write!(f, "-Os"),
+            level => write!(f, "-O{}", level.level()),
         }
     }
+}
 
-    fn create_function(
-        &mut self,
-        context: *mut llvm::LlvmContext,
-        name: &str,
-        args: &[String],
-    ) -> *mut llvm::Function {
-        let name = CString::new(name).unwrap();
-        let mut args: Vec<CString> = args
-            .iter()
-            .map(|s| CString::new(s.as_str()).unwrap())
-            .collect();
-        let mut args: Vec<*const i8> = args.iter_mut().map(|s| s.as_ptr()).collect();
+/// Parses the part after `-O`.
+impl FromStr for OptLevel {
+    type Err = String;
 
-        unsafe {
-            llvm::module_create_function(
-                context,
-                self.inner,
-                name.as_ptr(),
-                args.as_mut_ptr(),
-                args.len(),
-            )
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            "3" => Ok(OptLevel::O3),
+            "s" => Ok(OptLevel::Os),
+            _ => Err(format!(
+                "unknown optimization level `{}`, expected one of 0, 1, 2, 3 or s",
+                s
+            )),
         }
     }
 }
 
-struct IrBuilder {
-    inner: *mut llvm::IrBuilder,
+#[derive(Debug, Clone, PartialEq)]
+pub enum CodegenError {
+    /// A variable that isn't an argument or bound by `for` or `var`.
+    UnknownVariable { name: String, span: Span },
+    /// A call to a function, or use of an operator, that was never defined or declared.
+    UnknownFunction { name: String, span: Span },
+    /// A call with a different number of arguments than the function takes.
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    /// A declaration or definition with a different number of arguments than an earlier one of
+    /// the same name.
+    ConflictingDeclaration {
+        name: String,
+        previous: usize,
+        found: usize,
+        span: Span,
+    },
+    /// A second definition of a function when compiling ahead of time, where there's no JIT to
+    /// replace the first one in.
+    Redefinition { name: String, span: Span },
+    /// A top-level expression when compiling ahead of time, where nothing can evaluate it.
+    TopLevelExpression { span: Span },
+    /// The JIT couldn't compile or find the code for an expression.
+    Jit { message: String, span: Span },
 }
 
-impl IrBuilder {
-    fn create_f_add(
-        &self,
-        lhs: *mut llvm::Value,
-        rhs: *mut llvm::Value,
-        op: &'static str,
-    ) -> *mut llvm::Value {
-        unsafe {
-            let s = CString::new(op).unwrap();
-            llvm::builder_create_f_add(self.inner, lhs, rhs, s.as_ptr())
+impl CodegenError {
+    pub fn span(&self) -> Span {
+        match self {
+            CodegenError::UnknownVariable { span, .. } => *span,
+            CodegenError::UnknownFunction { span, .. } => *span,
+            CodegenError::ArityMismatch { span, .. } => *span,
+            CodegenError::ConflictingDeclaration { span, .. } => *span,
+            CodegenError::Redefinition { span, .. } => *span,
+            CodegenError::TopLevelExpression { span } => *span,
+            CodegenError::Jit { span, .. } => *span,
         }
     }
+}
 
-    fn create_f_sub(
-        &self,
-        lhs: *mut llvm::Value,
-        rhs: *mut llvm::Value,
-        op: &'static str,
-    ) -> *mut llvm::Value {
-        unsafe {
-            let s = CString::new(op).unwrap();
-            llvm::builder_create_f_sub(self.inner, lhs, rhs, s.as_ptr())
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodegenError::UnknownVariable { name, .. } => {
+                write!(f, "cannot find variable `{}`", name)
+            }
+            CodegenError::UnknownFunction { name, .. } => {
+                write!(f, "cannot find function `{}`", name)
+            }
+            CodegenError::ArityMismatch {
+                name,
+                expected,
+                found,
+                ..
+            } => write!(
+                f,
+                "function `{}` takes {} argument{}, but {} {} supplied",
+                name,
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                found,
+                if *found == 1 { "was" } else { "were" }
+            ),
+            CodegenError::ConflictingDeclaration {
+                name,
+                previous,
+                found,
+                ..
+            } => write!(
+                f,
+                "`{}` was declared with {} argument{}, but this takes {}",
+                name,
+                previous,
+                if *previous == 1 { "" } else { "s" },
+                found
+            ),
+            CodegenError::Redefinition { name, .. } => {
+                write!(f, "function `{}` is defined more than once", name)
+            }
+            CodegenError::TopLevelExpression { .. } => {
+                write!(f, "top-level expressions can't be compiled ahead of time")
+            }
+            CodegenError::Jit { message, .. } => write!(f, "{}", message),
         }
     }
+}
 
-    fn create_f_mul(
-        &self,
-        lhs: *mut llvm::Value,
-        rhs: *mut llvm::Value,
-        op: &'static str,
-    ) -> *mut llvm::Value {
-        unsafe {
-            let s = CString::new(op).unwrap();
-            llvm::builder_create_f_mul(self.inner, lhs, rhs, s.as_ptr())
-        }
-    }
+impl Error for CodegenError {}
 
-    fn create_f_cmp_lt(
-        &self,
-        context: *mut llvm::LlvmContext,
-        lhs: *mut llvm::Value,
-        rhs: *mut llvm::Value,
-        op: &'static str,
-    ) -> *mut llvm::Value {
-        unsafe {
-            let s = CString::new(op).unwrap();
-            llvm::builder_create_f_cmp_lt(context, self.inner, lhs, rhs, s.as_ptr())
+impl From<CodegenError> for Diagnostic {
+    fn from(err: CodegenError) -> Self {
+        let (message, span) = (err.to_string(), err.span());
+        match err {
+            CodegenError::UnknownVariable { .. } => Diagnostic::error("E0200", message, span)
+                .with_label("not found in this scope".to_string()),
+            CodegenError::UnknownFunction { .. } => Diagnostic::error("E0201", message, span)
+                .with_label("not defined or declared with `extern`".to_string()),
+            CodegenError::ArityMismatch { expected, .. } => {
+                Diagnostic::error("E0202", message, span)
+                    .with_label(format!("expected {} argument(s)", expected))
+            }
+            CodegenError::ConflictingDeclaration { name, .. } => {
+                Diagnostic::error("E0204", message, span)
+                    .with_label("conflicting declaration".to_string())
+                    .with_note(format!(
+                        "a redefinition of `{}` must keep the same arguments",
+                        name
+                    ))
+            }
+            CodegenError::Redefinition { .. } => Diagnostic::error("E0205", message, span)
+                .with_label("redefined here".to_string())
+                .with_note("only the REPL and the JIT can replace a definition".to_string()),
+            CodegenError::TopLevelExpression { .. } => Diagnostic::error("E0206", message, span)
+                .with_label("not inside a function".to_string())
+                .with_note("call it from a `def` and pick that as the entry function".to_string()),
+            CodegenError::Jit { .. } => Diagnostic::error("E0203", message, span)
+                .with_label("while evaluating this expression".to_string()),
         }
     }
+}
 
-    fn create_call(
-        &self,
-        function: *mut llvm::Function,
-        mut args: Vec<*mut llvm::Value>,
-    ) -> *mut llvm::Value {
-        unsafe {
-            let s = CString::new("calltmp").unwrap();
-            llvm::builder_create_call(
-                self.inner,
-                function,
-                args.as_mut_ptr(),
-                args.len(),
-                s.as_ptr(),
-            )
-        }
+// the source file that debug info refers to
+struct SourceFile {
+    name: String,
+    directory: String,
+}
+
+/// What to generate code for. Anything left out is taken from the machine we're running on, or
+/// is the target's default when cross-compiling.
+#[derive(Debug, Clone, Default)]
+pub struct Target {
+    /// A target triple like `aarch64-unknown-linux-gnu`.
+    pub triple: Option<String>,
+    pub cpu: Option<String>,
+    /// Comma-separated features like `+neon,-fp-armv8`.
+    pub features: Option<String>,
+}
+
+impl Target {
+    pub fn create_machine(&self, opt_level: OptLevel) -> Result<TargetMachine, String> {
+        TargetMachine::new(
+            self.triple.as_deref(),
+            self.cpu.as_deref(),
+            self.features.as_deref(),
+            opt_level.level(),
+        )
     }
 
-    fn create_ret(&self, value: *mut llvm::Value) {
-        unsafe {
-            llvm::builder_create_ret(self.inner, value);
-        }
+    /// Whether the code runs on the machine we're running on, so that the system C compiler can
+    /// link it.
+    pub fn is_host(&self) -> bool {
+        self.triple.as_deref().map_or(true, TargetMachine::is_host)
     }
 }
 
-pub fn print_value(value: *mut llvm::Value) {
-    unsafe { llvm::print_value(value) }
+// a function whose code is in the JIT, kept around so that it can be compiled again
+struct Definition {
+    node: FunctionAstNode,
+    // every function and operator the body calls
+    callees: HashSet<String>,
+    // `None` until the module holding the function is handed to the JIT
+    tracker: Option<ResourceTracker>,
 }
 
-pub fn print_function(function: *mut llvm::Function) {
-    unsafe { llvm::print_function(function) }
+// fields are dropped in order, so the debug info and pass manager go before the module they
+// refer to
+pub struct CodegenContext<'ctx> {
+    context: &'ctx Context,
+    // the debug info of the current module
+    debug_info: Option<DebugInfo<'ctx>>,
+    function_pass_manager: FunctionPassManager<'ctx>,
+    module: Module<'ctx>,
+    // every function defined so far, each compiled in a module of its own so that it can be
+    // replaced by a redefinition
+    definitions: HashMap<String, Definition>,
+    // functions of the host program defined with `define_host_function`
+    host_functions: HashMap<String, ResourceTracker>,
+    // `None` when compiling ahead of time, in which case everything goes in one module
+    jit: Option<Jit>,
+    opt_level: OptLevel,
+    // where the code comes from, when generating debug info
+    source: Option<SourceFile>,
+    // off while compiling code that isn't from the source file, which then has no debug info
+    source_debug_info: bool,
+    // every function declared so far, so that it can be declared again in later modules
+    prototypes: HashMap<String, PrototypeAstNode>,
+    // the functions of the prelude that the program hasn't defined or declared itself
+    prelude: HashSet<String>,
+    // the definition in the current module, if any
+    pending: Option<String>,
+    // definitions that called a function which has since been redefined
+    stale: Vec<FunctionAstNode>,
+    // the functions called by the function being compiled
+    callees: HashSet<String>,
 }
 
-pub struct CodegenContext {
-    context: *mut llvm::LlvmContext,
-    builder: IrBuilder,
-    module: Module,
-    named_values: HashMap<String, *mut llvm::Value>,
+// compiles the body of one function, borrowing the module it goes in for as long as it takes
+struct FunctionCodegen<'a, 'm> {
+    context: &'m Context,
+    module: &'m Module<'m>,
+    builder: Builder<'m>,
+    debug_info: Option<&'a DebugInfo<'m>>,
+    function: Function<'m>,
+    prototypes: &'a HashMap<String, PrototypeAstNode>,
+    // the functions called by the function being compiled
+    callees: &'a mut HashSet<String>,
+    // the stack slot of every variable in scope
+    named_values: HashMap<String, Value<'m>>,
 }
 
-impl CodegenContext {
-    pub fn codegen_expr(&mut self, node: ExprAstNode) -> *mut llvm::Value {
-        unsafe {
-            match node {
-                ExprAstNode::Number(NumberExprAstNode { value }) => {
-                    llvm::get_constant_fp(self.context, value)
+impl<'a, 'm> FunctionCodegen<'a, 'm> {
+    fn codegen_expr(&mut self, node: ExprAstNode) -> Result<Value<'m>, CodegenError> {
+        let debug_info = match &self.debug_info {
+            Some(debug_info) => debug_info,
+            None => return self.codegen_expr_inner(node),
+        };
+
+        // code generated for the parent after this expression is back at the parent's location
+        let parent = self.builder.debug_location();
+        let span = node.span();
+        debug_info.set_location(&self.builder, span.line, span.column);
+        let value = self.codegen_expr_inner(node);
+        self.builder.set_debug_location(parent);
+
+        value
+    }
+
+    fn codegen_expr_inner(&mut self, node: ExprAstNode) -> Result<Value<'m>, CodegenError> {
+        Ok(match node {
+            ExprAstNode::Number(NumberExprAstNode { value, .. }) => self.context.const_f64(value),
+            ExprAstNode::Variable(VariableExprAstNode { name, span }) => {
+                let variable = self.lookup_variable(name.clone(), span)?;
+                self.builder.create_load(variable, &name)
+            }
+            ExprAstNode::Unary(UnaryExprAstNode {
+                op: Token::Minus,
+                operand,
+                ..
+            }) => {
+                let operand = self.codegen_expr(*operand)?;
+                self.builder.create_f_neg(operand, "negtmp")
+            }
+            ExprAstNode::Unary(UnaryExprAstNode { op, operand, span }) => {
+                let operand = self.codegen_expr(*operand)?;
+
+                let name = format!("unary{}", op.operator().unwrap());
+                let function = self.get_function(&name, 1, span)?;
+                self.builder.create_call(function, &[operand])
+            }
+            ExprAstNode::Binary(BinaryExprAstNode {
+                op: Token::Equals,
+                lhs,
+                rhs,
+                ..
+            }) => {
+                // the parser only allows a variable on the left of an assignment
+                let (name, span) = match *lhs {
+                    ExprAstNode::Variable(VariableExprAstNode { name, span }) => (name, span),
+                    _ => unreachable!(),
+                };
+
+                let value = self.codegen_expr(*rhs)?;
+                let variable = self.lookup_variable(name, span)?;
+                self.builder.create_store(value, variable);
+
+                value
+            }
+            ExprAstNode::Binary(BinaryExprAstNode {
+                op: op @ (Token::And | Token::Or),
+                lhs,
+                rhs,
+                ..
+            }) => self.codegen_logical(op, *lhs, *rhs)?,
+            ExprAstNode::Binary(BinaryExprAstNode { lhs, rhs, op, span }) => {
+                let lhs = self.codegen_expr(*lhs)?;
+                let rhs = self.codegen_expr(*rhs)?;
+
+                // ordering comparisons are unordered, so true when either side is NaN, but
+                // `==` follows C in never considering NaN equal to anything
+                let predicate = match op {
+                    Token::EqualEqual => Some(FloatPredicate::Oeq),
+                    Token::NotEqual => Some(FloatPredicate::Une),
+                    Token::LessThan => Some(FloatPredicate::Ult),
+                    Token::LessEqual => Some(FloatPredicate::Ule),
+                    Token::GreaterThan => Some(FloatPredicate::Ugt),
+                    Token::GreaterEqual => Some(FloatPredicate::Uge),
+                    _ => None,
+                };
+                if let Some(predicate) = predicate {
+                    let cmp = self.builder.create_f_cmp(predicate, lhs, rhs, "cmptmp");
+                    return Ok(self.builder.create_ui_to_fp(cmp, "booltmp"));
                 }
-                ExprAstNode::Variable(VariableExprAstNode { name }) => self.named_values[&name],
-                ExprAstNode::Binary(BinaryExprAstNode { lhs, rhs, op }) => {
-                    let lhs = self.codegen_expr(*lhs);
-                    let rhs = self.codegen_expr(*rhs);
-                    match op {
-                        Token::Plus => self.builder.create_f_add(lhs, rhs, "addtmp"),
-                        Token::Minus => self.builder.create_f_sub(lhs, rhs, "subtmp"),
-                        Token::Star => self.builder.create_f_mul(lhs, rhs, "multmp"),
-                        Token::LessThan => {
-                            self.builder
-                                .create_f_cmp_lt(self.context, lhs, rhs, "booltmp")
-                        }
-                        _ => unreachable!(),
+
+                match op {
+                    Token::Plus => self.builder.create_f_add(lhs, rhs, "addtmp"),
+                    Token::Minus => self.builder.create_f_sub(lhs, rhs, "subtmp"),
+                    Token::Star => self.builder.create_f_mul(lhs, rhs, "multmp"),
+                    Token::Slash => self.builder.create_f_div(lhs, rhs, "divtmp"),
+                    // anything else is a user-defined operator, which is just a function
+                    op => {
+                        let name = format!("binary{}", op.operator().unwrap());
+                        let function = self.get_function(&name, 2, span)?;
+                        self.builder.create_call(function, &[lhs, rhs])
                     }
                 }
-                ExprAstNode::Call(CallExprAstNode { callee, args }) => {
-                    let function = self.module.get_function(&callee);
-                    let args: Vec<*mut llvm::Value> =
-                        args.into_iter().map(|arg| self.codegen_expr(arg)).collect();
+            }
+            ExprAstNode::Call(CallExprAstNode { callee, args, span }) => {
+                let function = self.get_function(&callee, args.len(), span)?;
+                let args = args
+                    .into_iter()
+                    .map(|arg| self.codegen_expr(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                self.builder.create_call(function, &args)
+            }
+            ExprAstNode::If(IfExprAstNode {
+                cond, then, else_, ..
+            }) => {
+                let cond = self.codegen_expr(*cond)?;
+                let zero = self.context.const_f64(0.0);
+                let cond = self
+                    .builder
+                    .create_f_cmp(FloatPredicate::One, cond, zero, "ifcond");
+
+                let function = self.function;
+                let then_block = function.append_basic_block(self.context, "then");
+                let else_block = function.append_basic_block(self.context, "else");
+                let merge_block = function.append_basic_block(self.context, "ifcont");
+                self.builder.create_cond_br(cond, then_block, else_block);
+
+                // codegen of a branch can change the current block, so the phi has to use
+                // whichever block each branch ends in
+                then_block.move_to_end();
+                self.builder.set_insert_point(then_block);
+                let then = self.codegen_expr(*then)?;
+                self.builder.create_br(merge_block);
+                let then_block = self.builder.get_insert_block();
+
+                else_block.move_to_end();
+                self.builder.set_insert_point(else_block);
+                let else_ = self.codegen_expr(*else_)?;
+                self.builder.create_br(merge_block);
+                let else_block = self.builder.get_insert_block();
+
+                merge_block.move_to_end();
+                self.builder.set_insert_point(merge_block);
+                let phi = self.builder.create_phi(2, "iftmp");
+                phi.add_incoming(then, then_block);
+                phi.add_incoming(else_, else_block);
+
+                phi.as_value()
+            }
+            ExprAstNode::For(ForExprAstNode {
+                var,
+                start,
+                end,
+                step,
+                body,
+                ..
+            }) => {
+                let function = self.function;
+                let variable = self.builder.create_entry_block_alloca(function, &var);
+
+                let start = self.codegen_expr(*start)?;
+                self.builder.create_store(start, variable);
+
+                let loop_block = function.append_basic_block(self.context, "loop");
+                self.builder.create_br(loop_block);
+                self.builder.set_insert_point(loop_block);
+
+                // the loop variable shadows any existing variable of the same name
+                let shadowed = self.named_values.insert(var.clone(), variable);
+
+                // the value of the body is ignored, the loop always evaluates to 0.0
+                self.codegen_expr(*body)?;
+
+                let step = match step {
+                    Some(step) => self.codegen_expr(*step)?,
+                    None => self.context.const_f64(1.0),
+                };
+
+                // the end condition is evaluated before the increment, but the body could
+                // have assigned to the variable so it has to be reloaded here
+                let end = self.codegen_expr(*end)?;
+                let current = self.builder.create_load(variable, &var);
+                let next = self.builder.create_f_add(current, step, "nextvar");
+                self.builder.create_store(next, variable);
+
+                let zero = self.context.const_f64(0.0);
+                let end = self
+                    .builder
+                    .create_f_cmp(FloatPredicate::One, end, zero, "loopcond");
+
+                let after_block = function.append_basic_block(self.context, "afterloop");
+                self.builder.create_cond_br(end, loop_block, after_block);
+                self.builder.set_insert_point(after_block);
+
+                match shadowed {
+                    Some(value) => self.named_values.insert(var, value),
+                    None => self.named_values.remove(&var),
+                };
+
+                self.context.const_f64(0.0)
+            }
+            ExprAstNode::Var(VarExprAstNode { vars, body, .. }) => {
+                let function = self.function;
+
+                let mut shadowed = Vec::new();
+                for (name, init) in vars {
+                    // the initializer can't see the variable it initializes, so `var a = a`
+                    // refers to an outer `a`
+                    let init = match init {
+                        Some(init) => self.codegen_expr(init)?,
+                        None => self.context.const_f64(0.0),
+                    };
+
+                    let variable = self.builder.create_entry_block_alloca(function, &name);
+                    self.builder.create_store(init, variable);
+
+                    let previous = self.named_values.insert(name.clone(), variable);
+                    shadowed.push((name, previous));
+                }
+
+                let body = self.codegen_expr(*body)?;
 
-                    self.builder.create_call(function, args)
+                // restore in reverse so a name bound twice gets its outermost value back
+                for (name, previous) in shadowed.into_iter().rev() {
+                    match previous {
+                        Some(value) => self.named_values.insert(name, value),
+                        None => self.named_values.remove(&name),
+                    };
                 }
+
+                body
+            }
+        })
+    }
+
+    // `and` and `or` only evaluate their rhs when the lhs doesn't already decide the result
+    fn codegen_logical(
+        &mut self,
+        op: Token,
+        lhs: ExprAstNode,
+        rhs: ExprAstNode,
+    ) -> Result<Value<'m>, CodegenError> {
+        let zero = self.context.const_f64(0.0);
+
+        let lhs = self.codegen_expr(lhs)?;
+        let lhs = self
+            .builder
+            .create_f_cmp(FloatPredicate::One, lhs, zero, "lhscond");
+
+        let function = self.function;
+        let lhs_block = self.builder.get_insert_block();
+        let rhs_block = function.append_basic_block(self.context, "rhs");
+        let merge_block = function.append_basic_block(self.context, "logicalcont");
+        let short_circuit = match op {
+            Token::And => {
+                self.builder.create_cond_br(lhs, rhs_block, merge_block);
+                0.0
+            }
+            Token::Or => {
+                self.builder.create_cond_br(lhs, merge_block, rhs_block);
+                1.0
             }
+            _ => unreachable!(),
+        };
+
+        rhs_block.move_to_end();
+        self.builder.set_insert_point(rhs_block);
+        let rhs = self.codegen_expr(rhs)?;
+        let rhs = self
+            .builder
+            .create_f_cmp(FloatPredicate::One, rhs, zero, "rhscond");
+        let rhs = self.builder.create_ui_to_fp(rhs, "booltmp");
+        self.builder.create_br(merge_block);
+        let rhs_block = self.builder.get_insert_block();
+
+        merge_block.move_to_end();
+        self.builder.set_insert_point(merge_block);
+        let phi = self.builder.create_phi(2, "logicaltmp");
+        phi.add_incoming(self.context.const_f64(short_circuit), lhs_block);
+        phi.add_incoming(rhs, rhs_block);
+
+        Ok(phi.as_value())
+    }
+
+    fn lookup_variable(&self, name: String, span: Span) -> Result<Value<'m>, CodegenError> {
+        match self.named_values.get(&name) {
+            Some(variable) => Ok(*variable),
+            None => Err(CodegenError::UnknownVariable { name, span }),
         }
     }
 
-    pub fn codegen_prototype(&mut self, node: PrototypeAstNode) -> *mut llvm::Function {
-        self.module
-            .create_function(self.context, &node.name, node.args.as_slice())
+    // look in the current module first, then redeclare functions from earlier modules
+    fn get_function(
+        &mut self,
+        name: &str,
+        arity: usize,
+        span: Span,
+    ) -> Result<Function<'m>, CodegenError> {
+        let prototype = match self.prototypes.get(name) {
+            Some(prototype) => prototype,
+            None => {
+                return Err(CodegenError::UnknownFunction {
+                    name: name.to_string(),
+                    span,
+                })
+            }
+        };
+        if prototype.args.len() != arity {
+            return Err(CodegenError::ArityMismatch {
+                name: name.to_string(),
+                expected: prototype.args.len(),
+                found: arity,
+                span,
+            });
+        }
+
+        self.callees.insert(name.to_string());
+
+        Ok(self.module.get_function(name).unwrap_or_else(|| {
+            self.module
+                .create_function(self.context, &prototype.name, &prototype.args)
+        }))
+    }
+
+    // the body of the function, with a stack slot for each argument so that it can be assigned to
+    fn codegen_body(
+        &mut self,
+        args: Vec<String>,
+        span: Span,
+        body: ExprAstNode,
+    ) -> Result<(), CodegenError> {
+        for (index, (arg, value)) in args.into_iter().zip(self.function.args()).enumerate() {
+            let variable = self.builder.create_entry_block_alloca(self.function, &arg);
+            self.builder.create_store(value, variable);
+            if let Some(debug_info) = self.debug_info {
+                debug_info.declare_parameter(
+                    &self.builder,
+                    variable,
+                    &arg,
+                    index,
+                    span.line,
+                    span.column,
+                );
+            }
+            self.named_values.insert(arg, variable);
+        }
+
+        let retval = self.codegen_expr(body)?;
+        self.builder.create_ret(retval);
+
+        Ok(())
+    }
+}
+
+impl<'ctx> CodegenContext<'ctx> {
+    pub fn codegen_prototype(
+        &mut self,
+        node: PrototypeAstNode,
+    ) -> Result<Function<'_>, CodegenError> {
+        if let Some(previous) = self.prototypes.get(&node.name) {
+            if previous.args.len() != node.args.len() {
+                return Err(CodegenError::ConflictingDeclaration {
+                    name: node.name,
+                    previous: previous.args.len(),
+                    found: node.args.len(),
+                    span: node.span,
+                });
+            }
+        }
+
+        // an existing function is a declaration, from an `extern` or a call, for a definition to
+        // fill in: the JIT gets a module of its own for each definition, and ahead of time the
+        // second definition of a function is an error
+        let (module, context) = (&self.module, self.context);
+        let function = module
+            .get_function(&node.name)
+            .unwrap_or_else(|| module.create_function(context, &node.name, node.args.as_slice()));
+        self.prototypes.insert(node.name.clone(), node);
+
+        Ok(function)
+    }
+
+    /// Compile a `def`. Defining a function replaces its code in the JIT, or the builtin or
+    /// library function of the same name, along with the code of every function that calls it.
+    pub fn codegen_function(
+        &mut self,
+        node: FunctionAstNode,
+    ) -> Result<Function<'_>, CodegenError> {
+        let (name, span) = (node.prototype.name.clone(), node.span);
+        let jit_error = |message| CodegenError::Jit { message, span };
+
+        if self.jit.is_none() && self.definitions.contains_key(&name) {
+            return Err(CodegenError::Redefinition {
+                name,
+                span: node.prototype.span,
+            });
+        }
+
+        self.flush().map_err(jit_error)?;
+        self.compile_function(node.clone())?;
+
+        // callers may have been linked to an earlier definition, or to a function outside the
+        // JIT of the same name
+        if self.jit.is_some() {
+            self.remove_dependents(&name).map_err(jit_error)?;
+        }
+
+        self.define(node);
+        if self.jit.is_some() {
+            let callees = self.definitions[&name].callees.clone();
+            self.optimize_module(&callees);
+        }
+
+        Ok(self.module.get_function(&name).unwrap())
+    }
+
+    /// Define `name` as a function of the host program that takes `arity` arguments, and declare
+    /// it. Calling it calls the C function at `trampoline`, with `data` in front of the arguments.
+    /// Like a `def`, this replaces any earlier function of the same name.
+    pub fn define_host_function(
+        &mut self,
+        name: &str,
+        arity: usize,
+        trampoline: usize,
+        data: usize,
+    ) -> Result<(), String> {
+        if self.jit.is_none() {
+            return Err("only the JIT can call functions of the host program".to_string());
+        }
+
+        let prototype = PrototypeAstNode {
+            name: name.to_string(),
+            args: (0..arity).map(|index| format!("x{}", index)).collect(),
+            kind: PrototypeKind::Function,
+            span: Span::default(),
+        };
+        self.take_from_prelude(&prototype)
+            .map_err(|err| err.to_string())?;
+        self.codegen_prototype(prototype)
+            .map_err(|err| err.to_string())?;
+
+        // callers of an earlier function of the same name have to be compiled again, once there's
+        // nothing of it left in the current module either
+        self.flush()?;
+        self.remove_dependents(name)?;
+
+        let jit = self.jit.as_ref().unwrap();
+        let tracker =
+            jit.add_trampoline(self.context, name, arity, trampoline as u64, data as u64)?;
+        self.host_functions.insert(name.to_string(), tracker);
+
+        Ok(())
     }
 
-    // TODO prototype could have already been codegen'd so mix some Results in here
-    pub fn codegen_function(&mut self, node: FunctionAstNode) -> *mut llvm::Function {
+    /// Note that `name` comes from the prelude, which the program can replace.
+    pub fn add_to_prelude(&mut self, name: &str) {
+        self.prelude.insert(name.to_string());
+    }
+
+    /// Hand the function of `prototype` over from the prelude to the program, which is about to
+    /// define or declare it. A prelude function that takes a different number of arguments is
+    /// forgotten, along with the prelude functions that call it, as long as the program doesn't
+    /// call it too. Returns the names of the functions forgotten.
+    pub fn take_from_prelude(
+        &mut self,
+        prototype: &PrototypeAstNode,
+    ) -> Result<Vec<String>, CodegenError> {
+        let name = &prototype.name;
+        let jit_error = |message| CodegenError::Jit {
+            message,
+            span: prototype.span,
+        };
+        if !self.prelude.contains(name) {
+            return Ok(Vec::new());
+        }
+
+        let same_arity = self
+            .prototypes
+            .get(name)
+            .map_or(true, |previous| previous.args.len() == prototype.args.len());
+        let dependents = self.dependents(name);
+        if !same_arity
+            && dependents
+                .iter()
+                .any(|dependent| !self.prelude.contains(dependent))
+        {
+            // left for `codegen_prototype` to report as a conflict
+            return Ok(Vec::new());
+        }
+        self.prelude.remove(name);
+        if same_arity {
+            return Ok(Vec::new());
+        }
+
+        // in the JIT the current module is then empty. Ahead of time the prelude's definitions
+        // aren't compiled until `finish`, so nothing calls the declaration
+        self.flush().map_err(jit_error)?;
+        let mut forgotten = Vec::new();
+        for dependent in dependents {
+            if let Some(definition) = self.definitions.remove(&dependent) {
+                if let Some(tracker) = definition.tracker {
+                    tracker.remove().map_err(jit_error)?;
+                }
+            }
+            if let Some(function) = self.module.get_function(&dependent) {
+                unsafe { function.erase() };
+            }
+            self.prototypes.remove(&dependent);
+            self.prelude.remove(&dependent);
+            forgotten.push(dependent);
+        }
+
+        Ok(forgotten)
+    }
+
+    // take the code of `name` and of everything that calls it out of the JIT. The callers are
+    // compiled again by the next `flush`.
+    fn remove_dependents(&mut self, name: &str) -> Result<(), String> {
+        if let Some(tracker) = self.host_functions.remove(name) {
+            tracker.remove()?;
+        }
+
+        for dependent in self.dependents(name) {
+            let definition = match self.definitions.remove(&dependent) {
+                Some(definition) => definition,
+                None => continue,
+            };
+            if let Some(tracker) = definition.tracker {
+                tracker.remove()?;
+            }
+            if dependent != name {
+                self.stale.push(definition.node);
+            }
+        }
+
+        Ok(())
+    }
+
+    // the function itself and everything that calls it, directly or not
+    fn dependents(&self, name: &str) -> HashSet<String> {
+        let mut dependents = HashSet::new();
+        dependents.insert(name.to_string());
+
+        loop {
+            let callers: Vec<String> = self
+                .definitions
+                .iter()
+                .filter(|(caller, definition)| {
+                    !dependents.contains(*caller) && !definition.callees.is_disjoint(&dependents)
+                })
+                .map(|(caller, _)| caller.clone())
+                .collect();
+            if callers.is_empty() {
+                return dependents;
+            }
+            dependents.extend(callers);
+        }
+    }
+
+    // record the function just compiled into the current module
+    fn define(&mut self, node: FunctionAstNode) {
+        let name = node.prototype.name.clone();
+        let definition = Definition {
+            node,
+            callees: mem::take(&mut self.callees),
+            tracker: None,
+        };
+
+        self.definitions.insert(name.clone(), definition);
+        self.pending = Some(name);
+    }
+
+    fn compile_function(&mut self, node: FunctionAstNode) -> Result<(), CodegenError> {
+        let name = node.prototype.name.clone();
         let args = node.prototype.args.clone();
-        let prototype = self.codegen_prototype(node.prototype);
+        let span = node.prototype.span;
+        let previous = self.prototypes.get(&name).cloned();
+        let declared = self.module.get_function(&name).is_some();
+        self.codegen_prototype(node.prototype)?;
+        let function = self.module.get_function(&name).unwrap();
 
-        unsafe { llvm::create_function_body(self.context, prototype, self.builder.inner) };
+        // a new builder has no debug location, and the setup of the arguments isn't at any place
+        // in the source
+        let builder = self.context.create_builder();
+        builder.create_function_body(function);
+        let debug_info = self.debug_info.as_ref().filter(|_| self.source_debug_info);
+        if let Some(debug_info) = debug_info {
+            debug_info.begin_function(function, span.line, node.body.span().line);
+        }
 
-        self.named_values.clear();
-        unsafe {
-            let mut ptr = llvm::get_function_args(prototype);
-            for arg in args {
-                self.named_values.insert(arg, *ptr);
-                ptr = ptr.add(1);
+        self.callees.clear();
+        let result = FunctionCodegen {
+            context: self.context,
+            module: &self.module,
+            builder,
+            debug_info,
+            function,
+            prototypes: &self.prototypes,
+            callees: &mut self.callees,
+            named_values: HashMap::new(),
+        }
+        .codegen_body(args, span, *node.body);
+
+        if let Err(err) = result {
+            // don't leave a half-built function behind in the module. Nothing of it is used
+            // again: the builder and the handles into the body went with the `FunctionCodegen`.
+            // A declaration from before may have calls to it, so only the body goes
+            unsafe {
+                if declared {
+                    function.delete_body();
+                } else {
+                    function.erase();
+                }
             }
+            match previous {
+                Some(previous) => self.prototypes.insert(name, previous),
+                None => self.prototypes.remove(&name),
+            };
+            return Err(err);
         }
 
-        let retval = self.codegen_expr(*node.body);
-        self.builder.create_ret(retval);
+        self.function_pass_manager.run(function);
 
-        prototype
+        Ok(())
     }
 
-    pub fn new() -> Self {
-        unsafe {
-            let context = llvm::get_context();
-            let module = Module {
-                inner: llvm::get_module(context),
+    pub fn opt_level(&self) -> OptLevel {
+        self.opt_level
+    }
+
+    /// Whether the functions compiled from now on come from the source file given for debug
+    /// info. Those that don't, like the prelude, get no debug info.
+    pub fn set_source_debug_info(&mut self, enabled: bool) {
+        self.source_debug_info = enabled;
+    }
+
+    /// Optimize everything compiled from now on at `opt_level`.
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.opt_level = opt_level;
+        self.reset_function_pass_manager();
+    }
+
+    // the pass manager is replaced along with the module, before it's dropped or given to the JIT
+    fn reset_function_pass_manager(&mut self) {
+        self.function_pass_manager =
+            unsafe { FunctionPassManager::new(&self.module, self.opt_level.level()) };
+    }
+
+    // run the module passes over the current module, which holds a single function for the JIT.
+    // The functions it calls are each in a module of their own, so for the inliner to see them
+    // they are copied into this one, marked as defined elsewhere
+    fn optimize_module(&mut self, callees: &HashSet<String>) {
+        if self.opt_level.level() >= 2 {
+            // the copies aren't at any place in the source as far as this module's debug info
+            // goes
+            let source_debug_info = mem::replace(&mut self.source_debug_info, false);
+            for callee in callees {
+                let node = match self.definitions.get(callee) {
+                    Some(definition) if definition.tracker.is_some() => definition.node.clone(),
+                    _ => continue,
+                };
+                // this compiled before, against the same declarations
+                if self.compile_function(node).is_ok() {
+                    let function = self.module.get_function(callee).unwrap();
+                    function.set_available_externally();
+                }
+            }
+            self.source_debug_info = source_debug_info;
+        }
+
+        self.module
+            .run_passes(self.opt_level.level(), self.opt_level.size_level());
+    }
+
+    // hand the current module over to the JIT and start a new one
+    fn add_module_to_jit(&mut self) -> Result<ResourceTracker, String> {
+        if let Some(debug_info) = self.debug_info.take() {
+            debug_info.finalize();
+        }
+        let module = mem::replace(
+            &mut self.module,
+            create_module(self.context, self.jit.as_ref()),
+        );
+        self.debug_info = self.create_debug_info();
+        self.reset_function_pass_manager();
+
+        let jit = self.jit.as_ref().expect("compiling ahead of time");
+        jit.add_module(self.context, module)
+    }
+
+    // put everything compiled so far in the JIT, then compile again whatever a redefinition
+    // left out of it, each in a module of its own
+    fn flush(&mut self) -> Result<(), String> {
+        if self.jit.is_none() {
+            return Ok(());
+        }
+
+        loop {
+            // the module is gone either way, and a definition whose code didn't make it into the
+            // JIT is forgotten so that nothing takes it for being there
+            let result = self.add_module_to_jit();
+            let pending = self.pending.take();
+            let tracker = match result {
+                Ok(tracker) => tracker,
+                Err(err) => {
+                    if let Some(name) = pending {
+                        self.definitions.remove(&name);
+                    }
+                    return Err(err);
+                }
             };
-            let builder = IrBuilder {
-                inner: llvm::get_builder(context),
+            if let Some(name) = pending {
+                self.definitions.get_mut(&name).unwrap().tracker = Some(tracker);
+            }
+
+            let node = match self.stale.pop() {
+                Some(node) => node,
+                None => return Ok(()),
             };
+            // this compiled before, and everything it calls is still declared with the same
+            // arguments
+            let name = node.prototype.name.clone();
+            self.compile_function(node.clone())
+                .map_err(|err| err.to_string())?;
+            self.define(node);
+            let callees = self.definitions[&name].callees.clone();
+            self.optimize_module(&callees);
+        }
+    }
+
+    /// Compile and run an anonymous top-level expression, returning its value.
+    pub fn evaluate(&mut self, node: FunctionAstNode) -> Result<f64, CodegenError> {
+        let (name, span) = (node.prototype.name.clone(), node.span);
+        let jit_error = |message| CodegenError::Jit { message, span };
+        if self.jit.is_none() {
+            return Err(CodegenError::TopLevelExpression { span });
+        }
+
+        // everything defined so far stays in the JIT, the expression itself is removed again
+        // once it has run
+        self.flush().map_err(jit_error)?;
+
+        let result = self.compile_function(node);
+        self.prototypes.remove(&name);
+        result?;
+        let callees = mem::take(&mut self.callees);
+        self.optimize_module(&callees);
+        let tracker = self.add_module_to_jit().map_err(jit_error)?;
+
+        let result = self
+            .jit
+            .as_ref()
+            .unwrap()
+            .lookup(&name)
+            .map(|address| unsafe {
+                let function: extern "C" fn() -> f64 = mem::transmute(address as usize);
+                function()
+            });
+        tracker.remove().map_err(jit_error)?;
+
+        result.map_err(jit_error)
+    }
+
+    /// Call a function defined or declared so far. Only functions of up to 6 arguments can be
+    /// called this way. There's no source to point at, so errors have an empty span.
+    pub fn call(&mut self, name: &str, args: &[f64]) -> Result<f64, CodegenError> {
+        let span = Span::default();
+        let jit_error = |message| CodegenError::Jit { message, span };
+        let prototype = match self.prototypes.get(name) {
+            Some(prototype) => prototype,
+            None => {
+                return Err(CodegenError::UnknownFunction {
+                    name: name.to_string(),
+                    span,
+                })
+            }
+        };
+        if prototype.args.len() != args.len() {
+            return Err(CodegenError::ArityMismatch {
+                name: name.to_string(),
+                expected: prototype.args.len(),
+                found: args.len(),
+                span,
+            });
+        }
+        if self.jit.is_none() {
+            return Err(jit_error(
+                "nothing can be called when compiling ahead of time".to_string(),
+            ));
+        }
+
+        self.flush().map_err(jit_error)?;
+        let address = self.jit.as_ref().unwrap().lookup(name).map_err(jit_error)? as usize;
+
+        // every function takes and returns doubles, so its arity is all there is to its type
+        unsafe {
+            Ok(match *args {
+                [] => mem::transmute::<_, extern "C" fn() -> f64>(address)(),
+                [a] => mem::transmute::<_, extern "C" fn(f64) -> f64>(address)(a),
+                [a, b] => mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(address)(a, b),
+                [a, b, c] => {
+                    mem::transmute::<_, extern "C" fn(f64, f64, f64) -> f64>(address)(a, b, c)
+                }
+                [a, b, c, d] => mem::transmute::<_, extern "C" fn(f64, f64, f64, f64) -> f64>(
+                    address,
+                )(a, b, c, d),
+                [a, b, c, d, e] => {
+                    mem::transmute::<_, extern "C" fn(f64, f64, f64, f64, f64) -> f64>(address)(
+                        a, b, c, d, e,
+                    )
+                }
+                [a, b, c, d, e, f] => mem::transmute::<
+                    _,
+                    extern "C" fn(f64, f64, f64, f64, f64, f64) -> f64,
+                >(address)(a, b, c, d, e, f),
+                _ => {
+                    return Err(jit_error(format!(
+                        "`{}` takes {} arguments, but only functions of up to 6 can be called",
+                        name,
+                        args.len()
+                    )))
+                }
+            })
+        }
+    }
+
+    /// Check the module compiled ahead of time and run the whole-module optimizations, once all
+    /// of the program has been compiled and before it is written out.
+    pub fn finish(&mut self) -> Result<(), String> {
+        if let Some(debug_info) = self.debug_info.take() {
+            debug_info.finalize();
+        }
+
+        // calls to the builtins go to the runtime archive, unless the program defines its own
+        for (name, _) in runtime::builtins() {
+            if self.definitions.contains_key(name) {
+                continue;
+            }
+            if let Some(function) = self.module.get_function(name) {
+                function.set_name(&runtime::archive_symbol(name));
+            }
+        }
+
+        self.verify()?;
+        self.module
+            .run_passes(self.opt_level.level(), self.opt_level.size_level());
+
+        Ok(())
+    }
+
+    fn verify(&self) -> Result<(), String> {
+        self.module
+            .verify()
+            .map_err(|message| format!("generated invalid code:\n{}", message))
+    }
 
-            CodegenContext {
-                context,
-                builder,
-                module,
-                named_values: HashMap::new(),
+    // the writers check the module again themselves, as nothing stops them being called without
+    // `finish`, or after compiling more
+    pub fn write_object(&self, target_machine: &TargetMachine, path: &str) -> Result<(), String> {
+        self.verify()?;
+        target_machine.emit_object(&self.module, path)
+    }
+
+    /// Write the module as textual IR, as in a `.ll` file.
+    pub fn write_ir(&self, path: &str) -> Result<(), String> {
+        self.verify()?;
+        self.module.write_ir(path)
+    }
+
+    /// Write the module as bitcode, as in a `.bc` file.
+    pub fn write_bitcode(&self, path: &str) -> Result<(), String> {
+        self.verify()?;
+        self.module.write_bitcode(path)
+    }
+
+    /// Make the functions of the shared library at `path` available to `extern` declarations.
+    pub fn load_library(&self, path: &str) -> Result<(), String> {
+        match &self.jit {
+            Some(jit) => jit.load_library(path),
+            None => {
+                Err("only the JIT can load libraries, executables link them instead".to_string())
             }
         }
     }
+
+    /// Whether anything compiled into the current module calls `name`.
+    pub fn is_called(&self, name: &str) -> bool {
+        self.module
+            .get_function(name)
+            .map_or(false, Function::is_used)
+    }
+
+    /// Take the declaration of `name` out of the current module, if nothing calls it and it isn't
+    /// defined.
+    pub fn remove_unused(&mut self, name: &str) {
+        if let Some(function) = self.module.get_function(name) {
+            if !function.is_used() && !self.definitions.contains_key(name) {
+                unsafe { function.erase() };
+                self.prototypes.remove(name);
+            }
+        }
+    }
+
+    /// The number of arguments of a function defined so far.
+    pub fn defined_arity(&self, name: &str) -> Option<usize> {
+        self.definitions
+            .get(name)
+            .map(|definition| definition.node.prototype.args.len())
+    }
+
+    // the debug info is finalized and dropped before its module is replaced
+    fn create_debug_info(&self) -> Option<DebugInfo<'ctx>> {
+        let source = self.source.as_ref()?;
+        Some(unsafe {
+            DebugInfo::new(
+                &self.module,
+                &source.name,
+                &source.directory,
+                self.opt_level != OptLevel::O0,
+            )
+        })
+    }
+
+    /// Compile for the JIT, one module per definition, with the builtins of the runtime available
+    /// to it. With a `debug_file`, the code has debug info referring to it.
+    pub fn new(
+        context: &'ctx Context,
+        opt_level: OptLevel,
+        debug_file: Option<&str>,
+    ) -> Result<Self, String> {
+        let jit = Jit::new(debug_file.is_some())?;
+        for (name, address) in runtime::builtins() {
+            jit.define_symbol(name, address as u64)?;
+        }
+
+        Ok(Self::with_jit(context, opt_level, Some(jit), debug_file))
+    }
+
+    /// Compile the whole program into a single module for `target_machine`.
+    pub fn ahead_of_time(
+        context: &'ctx Context,
+        opt_level: OptLevel,
+        target_machine: &TargetMachine,
+        debug_file: Option<&str>,
+    ) -> Self {
+        let codegen = Self::with_jit(context, opt_level, None, debug_file);
+        codegen.module.set_target(target_machine);
+
+        codegen
+    }
+
+    fn with_jit(
+        context: &'ctx Context,
+        opt_level: OptLevel,
+        jit: Option<Jit>,
+        debug_file: Option<&str>,
+    ) -> Self {
+        let source = debug_file.map(|name| SourceFile {
+            name: name.to_string(),
+            directory: env::current_dir()
+                .map(|directory| directory.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        });
+
+        let module = create_module(context, jit.as_ref());
+        let function_pass_manager = unsafe { FunctionPassManager::new(&module, opt_level.level()) };
+        let mut codegen = CodegenContext {
+            context,
+            debug_info: None,
+            function_pass_manager,
+            module,
+            definitions: HashMap::new(),
+            host_functions: HashMap::new(),
+            jit,
+            opt_level,
+            source,
+            source_debug_info: true,
+            prototypes: HashMap::new(),
+            prelude: HashSet::new(),
+            pending: None,
+            stale: Vec::new(),
+            callees: HashSet::new(),
+        };
+        codegen.debug_info = codegen.create_debug_info();
+
+        codegen
+    }
+}
+
+fn create_module<'ctx>(context: &'ctx Context, jit: Option<&Jit>) -> Module<'ctx> {
+    let module = context.create_module();
+    if let Some(jit) = jit {
+        module.set_data_layout(jit);
+    }
+
+    module
 }
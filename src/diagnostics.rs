@@ -0,0 +1,302 @@
+use std::io::{self, Write};
+
+use crate::lexer::Span;
+
+mod libc {
+    extern "C" {
+        pub fn isatty(fd: i32) -> i32;
+    }
+}
+
+const STDERR_FILENO: i32 = 2;
+
+const RED: &str = "\x1b[1;31m";
+const BOLD: &str = "\x1b[1m";
+const BLUE: &str = "\x1b[1;34m";
+const RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+/// A single message for the user, pointing at the source it's about.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub primary: Label,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: &'static str, message: String, span: Span) -> Self {
+        Diagnostic {
+            code,
+            message,
+            primary: Label {
+                span,
+                message: String::new(),
+            },
+            secondary: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    /// Text shown under the caret of the primary span.
+    pub fn with_label(mut self, message: String) -> Self {
+        self.primary.message = message;
+        self
+    }
+
+    pub fn with_secondary(mut self, span: Span, message: String) -> Self {
+        self.secondary.push(Label { span, message });
+        self
+    }
+
+    pub fn with_note(mut self, note: String) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn render(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+        source: &str,
+        color: bool,
+    ) -> io::Result<()> {
+        let paint = |style: &'static str| if color { style } else { "" };
+        let reset = paint(RESET);
+
+        writeln!(
+            out,
+            "{}error[{}]{}{}: {}{}",
+            paint(RED),
+            self.code,
+            reset,
+            paint(BOLD),
+            self.message,
+            reset
+        )?;
+
+        let mut labels: Vec<(&Label, bool)> = vec![(&self.primary, true)];
+        labels.extend(self.secondary.iter().map(|label| (label, false)));
+        labels.sort_by_key(|(label, _)| (label.span.line, label.span.column));
+
+        let gutter = labels
+            .iter()
+            .map(|(label, _)| label.span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+        let blue = paint(BLUE);
+
+        writeln!(
+            out,
+            "{}{:>width$}-->{} {}:{}:{}",
+            blue,
+            "",
+            reset,
+            name,
+            self.primary.span.line,
+            self.primary.span.column,
+            width = gutter
+        )?;
+        writeln!(out, "{}{:>width$} |{}", blue, "", reset, width = gutter)?;
+
+        let mut last_line = None;
+        for (label, primary) in labels {
+            let line = label.span.line;
+            let text = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+
+            if last_line != Some(line) {
+                writeln!(
+                    out,
+                    "{}{:>width$} |{} {}",
+                    blue,
+                    line,
+                    reset,
+                    text,
+                    width = gutter
+                )?;
+                last_line = Some(line);
+            }
+
+            // underline the span, but never past the end of its first line
+            let column = label.span.column.max(1) - 1;
+            let available = text.chars().count().saturating_sub(column);
+            let length = source
+                .get(label.span.start..label.span.end)
+                .map_or(0, |s| s.chars().take_while(|c| *c != '\n').count())
+                .min(available)
+                .max(1);
+            let (marker, style) = if primary { ('^', RED) } else { ('-', BLUE) };
+
+            writeln!(
+                out,
+                "{}{:>width$} |{} {:column$}{}{} {}{}",
+                blue,
+                "",
+                reset,
+                "",
+                paint(style),
+                marker.to_string().repeat(length),
+                label.message,
+                reset,
+                width = gutter,
+                column = column
+            )?;
+        }
+
+        for note in &self.notes {
+            writeln!(
+                out,
+                "{}{:>width$} ={} {}note{}: {}",
+                blue,
+                "",
+                reset,
+                paint(BOLD),
+                reset,
+                note,
+                width = gutter
+            )?;
+        }
+
+        writeln!(out)
+    }
+}
+
+/// Everything reported while compiling one piece of source, so that a single run can show all of
+/// its problems instead of stopping at the first.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn push(&mut self, diagnostic: impl Into<Diagnostic>) {
+        self.diagnostics.push(diagnostic.into());
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.diagnostics.is_empty()
+    }
+
+    pub fn emit(
+        &self,
+        out: &mut impl Write,
+        name: &str,
+        source: &str,
+        color: bool,
+    ) -> io::Result<()> {
+        for diagnostic in &self.diagnostics {
+            diagnostic.render(out, name, source, color)?;
+        }
+
+        Ok(())
+    }
+
+    /// Print everything to stderr, in colour if stderr is a terminal.
+    pub fn emit_to_stderr(&self, name: &str, source: &str) {
+        let color = unsafe { libc::isatty(STDERR_FILENO) != 0 };
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+
+        // nowhere left to report a failure to write to stderr
+        let _ = self.emit(&mut stderr, name, source, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(diagnostic: &Diagnostic, source: &str) -> String {
+        let mut out = Vec::new();
+        diagnostic
+            .render(&mut out, "test.ks", source, false)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn span(start: usize, end: usize, line: usize, column: usize) -> Span {
+        Span {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn renders_labels_and_notes() {
+        let source = "def f(x)\n  x + y;";
+        let diagnostic = Diagnostic::error(
+            "E0201",
+            "cannot find variable `y`".to_string(),
+            span(15, 16, 2, 7),
+        )
+        .with_label("not defined".to_string())
+        .with_secondary(span(5, 8, 1, 6), "parameters".to_string())
+        .with_note("declare it with `var`".to_string());
+
+        assert_eq!(
+            render(&diagnostic, source),
+            "error[E0201]: cannot find variable `y`\n\
+             \x20--> test.ks:2:7\n\
+             \x20 |\n\
+             1 | def f(x)\n\
+             \x20 |      --- parameters\n\
+             2 |   x + y;\n\
+             \x20 |       ^ not defined\n\
+             \x20 = note: declare it with `var`\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn caret_covers_the_span() {
+        let source = "1 + 1.2.3;";
+        let diagnostic = Diagnostic::error(
+            "E0001",
+            "invalid number `1.2.3`".to_string(),
+            span(4, 9, 1, 5),
+        );
+
+        assert_eq!(
+            render(&diagnostic, source),
+            "error[E0001]: invalid number `1.2.3`\n\
+             \x20--> test.ks:1:5\n\
+             \x20 |\n\
+             1 | 1 + 1.2.3;\n\
+             \x20 |     ^^^^^ \n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn caret_stops_at_the_end_of_the_line() {
+        let source = "def f(x\n  x;";
+        let diagnostic = Diagnostic::error("E0102", "unclosed `(`".to_string(), span(5, 11, 1, 6))
+            .with_label("opened here".to_string());
+
+        let rendered = render(&diagnostic, source);
+        assert!(rendered.contains("\n1 | def f(x\n  |      ^^ opened here\n"));
+        assert!(!rendered.contains("2 |"));
+    }
+
+    #[test]
+    fn gutter_fits_the_widest_line_number() {
+        let source = "\n".repeat(11) + "foo;";
+        let diagnostic = Diagnostic::error(
+            "E0201",
+            "cannot find variable `foo`".to_string(),
+            span(11, 14, 12, 1),
+        );
+
+        let rendered = render(&diagnostic, &source);
+        assert!(rendered.contains("\n  --> test.ks:12:1\n   |\n12 | foo;\n   | ^^^ \n"));
+    }
+}
@@ -0,0 +1,51 @@
+//! Rust functions and closures that Kaleidoscope code can call.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+
+/// A function or closure taking up to 6 `f64`s and returning one, which can be registered with
+/// `Session::register_fn`. `Args` only tells apart the implementations for each number of
+/// arguments.
+pub trait HostFn<Args>: 'static {
+    const ARITY: usize;
+
+    /// The address of a C function taking a pointer to `Self` followed by the arguments, which
+    /// calls `Self` with them.
+    fn trampoline() -> usize;
+}
+
+macro_rules! impl_host_fn {
+    (@f64 $arg:ident) => {
+        f64
+    };
+    ($arity:expr; $($arg:ident),*) => {
+        impl<F> HostFn<($(impl_host_fn!(@f64 $arg),)*)> for F
+        where
+            F: Fn($(impl_host_fn!(@f64 $arg)),*) -> f64 + 'static,
+        {
+            const ARITY: usize = $arity;
+
+            fn trampoline() -> usize {
+                // unwinding into code compiled by the JIT isn't possible, so a panic aborts
+                extern "C" fn trampoline<F>(function: *const F, $($arg: f64),*) -> f64
+                where
+                    F: Fn($(impl_host_fn!(@f64 $arg)),*) -> f64,
+                {
+                    let function = unsafe { &*function };
+                    panic::catch_unwind(AssertUnwindSafe(|| function($($arg),*)))
+                        .unwrap_or_else(|_| process::abort())
+                }
+
+                trampoline::<F> as usize
+            }
+        }
+    };
+}
+
+impl_host_fn!(0;);
+impl_host_fn!(1; a);
+impl_host_fn!(2; a, b);
+impl_host_fn!(3; a, b, c);
+impl_host_fn!(4; a, b, c, d);
+impl_host_fn!(5; a, b, c, d, e);
+impl_host_fn!(6; a, b, c, d, e, f);
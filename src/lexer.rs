@@ -1,33 +1,177 @@
+use std::fmt;
 use std::iter::Peekable;
 
-#[derive(Debug, PartialEq)]
+use crate::diagnostics::Diagnostic;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Eof,
     Def,
     Extern,
+    If,
+    Then,
+    Else,
+    For,
+    In,
+    Binary,
+    Unary,
+    Var,
+    And,
+    Or,
+    Equals,
+    EqualEqual,
+    NotEqual,
     LessThan,
+    LessEqual,
+    GreaterThan,
+    GreaterEqual,
     Plus,
     Minus,
     Star,
+    Slash,
     LParen,
     RParen,
     Comma,
     Semicolon,
     Identifier(String),
     Number(f64),
+    /// Any other ASCII punctuation, available for user-defined operators.
+    Operator(char),
+    /// Input the lexer could not make sense of. The offending characters have been consumed so
+    /// that lexing can carry on after them.
+    Error(LexError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    UnexpectedCharacter(char),
+    InvalidNumber(String),
+}
+
+impl LexError {
+    pub fn to_diagnostic(&self, span: Span) -> Diagnostic {
+        match self {
+            LexError::UnexpectedCharacter(_) => Diagnostic::error("E0001", self.to_string(), span)
+                .with_label("not valid in a program".to_string()),
+            LexError::InvalidNumber(_) => Diagnostic::error("E0002", self.to_string(), span)
+                .with_label("not a number".to_string())
+                .with_note("numbers are digits with at most one `.`".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::UnexpectedCharacter(c) => write!(f, "unexpected character `{}`", c),
+            LexError::InvalidNumber(s) => write!(f, "invalid number `{}`", s),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Eof => write!(f, "end of input"),
+            Token::Def => write!(f, "`def`"),
+            Token::Extern => write!(f, "`extern`"),
+            Token::If => write!(f, "`if`"),
+            Token::Then => write!(f, "`then`"),
+            Token::Else => write!(f, "`else`"),
+            Token::For => write!(f, "`for`"),
+            Token::In => write!(f, "`in`"),
+            Token::Binary => write!(f, "`binary`"),
+            Token::Unary => write!(f, "`unary`"),
+            Token::Var => write!(f, "`var`"),
+            Token::And => write!(f, "`and`"),
+            Token::Or => write!(f, "`or`"),
+            Token::Equals => write!(f, "`=`"),
+            Token::EqualEqual => write!(f, "`==`"),
+            Token::NotEqual => write!(f, "`!=`"),
+            Token::LessThan => write!(f, "`<`"),
+            Token::LessEqual => write!(f, "`<=`"),
+            Token::GreaterThan => write!(f, "`>`"),
+            Token::GreaterEqual => write!(f, "`>=`"),
+            Token::Plus => write!(f, "`+`"),
+            Token::Minus => write!(f, "`-`"),
+            Token::Star => write!(f, "`*`"),
+            Token::Slash => write!(f, "`/`"),
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::Comma => write!(f, "`,`"),
+            Token::Semicolon => write!(f, "`;`"),
+            Token::Identifier(name) => write!(f, "identifier `{}`", name),
+            Token::Number(value) => write!(f, "number `{}`", value),
+            Token::Operator(c) => write!(f, "`{}`", c),
+            Token::Error(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Token {
+    /// The spelling of the token if it can be used as an operator, which is how operators are
+    /// looked up in the precedence table and how user-defined operator functions are named.
+    pub fn operator(&self) -> Option<String> {
+        match self {
+            Token::And => Some("and".to_string()),
+            Token::Or => Some("or".to_string()),
+            Token::Equals => Some("=".to_string()),
+            Token::EqualEqual => Some("==".to_string()),
+            Token::NotEqual => Some("!=".to_string()),
+            Token::LessThan => Some("<".to_string()),
+            Token::LessEqual => Some("<=".to_string()),
+            Token::GreaterThan => Some(">".to_string()),
+            Token::GreaterEqual => Some(">=".to_string()),
+            Token::Plus => Some("+".to_string()),
+            Token::Minus => Some("-".to_string()),
+            Token::Star => Some("*".to_string()),
+            Token::Slash => Some("/".to_string()),
+            Token::Operator(c) => Some(c.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// A region of source text. `start` and `end` are byte offsets, `line` and `column` are the
+/// 1-based position of `start`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// The span covering both `self` and everything up to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
 }
 
 pub struct TokenReader<C: Iterator<Item = char>> {
     has_returned_eof: bool,
     reader: Peekable<C>,
+    offset: usize,
+    line: usize,
+    column: usize,
 }
 
 impl<C: Iterator<Item = char>> Iterator for TokenReader<C> {
-    type Item = Token;
+    type Item = SpannedToken;
 
     fn next(&mut self) -> Option<Self::Item> {
         let token = self.get_token();
-        match token {
+        match token.token {
             Token::Eof => {
                 if self.has_returned_eof {
                     None
@@ -42,6 +186,48 @@ impl<C: Iterator<Item = char>> Iterator for TokenReader<C> {
 }
 
 impl<C: Iterator<Item = char>> TokenReader<C> {
+    // consume one character, keeping track of where we are in the source
+    fn bump(&mut self) -> Option<char> {
+        let c = self.reader.next()?;
+
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
+        Some(c)
+    }
+
+    // consume the next character only if it is `expected`, for two-character operators
+    fn eat(&mut self, expected: char) -> bool {
+        if self.reader.peek() == Some(&expected) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    // a span starting at the current position, to be finished by `finish_span`
+    fn start_span(&self) -> Span {
+        Span {
+            start: self.offset,
+            end: self.offset,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    fn finish_span(&self, span: Span) -> Span {
+        Span {
+            end: self.offset,
+            ..span
+        }
+    }
+
     fn skip_whitespace_and_comments(&mut self) {
         let mut comment = self.reader.peek().map(|c| c == &'#').unwrap_or(false);
 
@@ -53,7 +239,7 @@ impl<C: Iterator<Item = char>> TokenReader<C> {
                 .map(|c| c.is_whitespace())
                 .unwrap_or(false)
         {
-            match self.reader.next() {
+            match self.bump() {
                 None => break,
                 Some(c) => {
                     if c == '\n' {
@@ -75,7 +261,7 @@ impl<C: Iterator<Item = char>> TokenReader<C> {
             match next {
                 Some(c) if c.is_alphanumeric() => {
                     s.push(*c);
-                    self.reader.next();
+                    self.bump();
                 }
                 _ => break,
             }
@@ -85,7 +271,7 @@ impl<C: Iterator<Item = char>> TokenReader<C> {
     }
 
     // should have already verified that the next character starts an number
-    fn get_number(&mut self) -> f64 {
+    fn get_number(&mut self) -> Result<f64, LexError> {
         let mut s = String::new();
 
         loop {
@@ -93,39 +279,58 @@ impl<C: Iterator<Item = char>> TokenReader<C> {
             match next {
                 Some(c) if c.is_numeric() || c == &'.' => {
                     s.push(*c);
-                    self.reader.next();
+                    self.bump();
                 }
                 _ => break,
             }
         }
 
-        s.parse().expect("Not a number.")
+        s.parse().map_err(|_| LexError::InvalidNumber(s))
     }
 
     fn get_non_eof_token(&mut self) -> Option<Token> {
-        self.skip_whitespace_and_comments();
-
         if self.reader.peek()?.is_alphabetic() {
             let identifier = self.get_identifier();
 
             Some(match identifier.as_str() {
                 "def" => Token::Def,
                 "extern" => Token::Extern,
+                "if" => Token::If,
+                "then" => Token::Then,
+                "else" => Token::Else,
+                "for" => Token::For,
+                "in" => Token::In,
+                "binary" => Token::Binary,
+                "unary" => Token::Unary,
+                "var" => Token::Var,
+                "and" => Token::And,
+                "or" => Token::Or,
                 _ => Token::Identifier(identifier),
             })
         } else if self.reader.peek()?.is_numeric() || self.reader.peek()? == &'.' {
-            Some(Token::Number(self.get_number()))
+            Some(match self.get_number() {
+                Ok(value) => Token::Number(value),
+                Err(err) => Token::Error(err),
+            })
         } else {
-            Some(match self.reader.next()? {
+            Some(match self.bump()? {
                 '+' => Token::Plus,
                 '-' => Token::Minus,
+                '<' if self.eat('=') => Token::LessEqual,
                 '<' => Token::LessThan,
+                '>' if self.eat('=') => Token::GreaterEqual,
+                '>' => Token::GreaterThan,
+                '=' if self.eat('=') => Token::EqualEqual,
+                '=' => Token::Equals,
+                '!' if self.eat('=') => Token::NotEqual,
+                '/' => Token::Slash,
                 '(' => Token::LParen,
                 ')' => Token::RParen,
                 ',' => Token::Comma,
                 ';' => Token::Semicolon,
                 '*' => Token::Star,
-                c => panic!("Unexpected character. {}", c),
+                c if c.is_ascii_punctuation() => Token::Operator(c),
+                c => Token::Error(LexError::UnexpectedCharacter(c)),
             })
         }
     }
@@ -134,13 +339,90 @@ impl<C: Iterator<Item = char>> TokenReader<C> {
         TokenReader {
             has_returned_eof: false,
             reader: reader.peekable(),
+            offset: 0,
+            line: 1,
+            column: 1,
         }
     }
 
-    pub fn get_token(&mut self) -> Token {
-        match self.get_non_eof_token() {
+    pub fn get_token(&mut self) -> SpannedToken {
+        self.skip_whitespace_and_comments();
+
+        let span = self.start_span();
+        let token = match self.get_non_eof_token() {
             None => Token::Eof,
             Some(token) => token,
+        };
+
+        SpannedToken {
+            token,
+            span: self.finish_span(span),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Vec<SpannedToken> {
+        TokenReader::new(source.chars()).collect()
+    }
+
+    #[test]
+    fn spans_track_lines_and_columns() {
+        let tokens = lex("def f(x)\n  x +\n\t1;");
+
+        let x = &tokens[5];
+        assert_eq!(x.token, Token::Identifier("x".to_string()));
+        assert_eq!(
+            x.span,
+            Span {
+                start: 11,
+                end: 12,
+                line: 2,
+                column: 3
+            }
+        );
+
+        let one = &tokens[7];
+        assert_eq!(one.token, Token::Number(1.0));
+        assert_eq!((one.span.line, one.span.column), (3, 2));
+        assert_eq!((one.span.start, one.span.end), (16, 17));
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_counted() {
+        let tokens = lex("# a comment\n\nfoo");
+
+        assert_eq!(tokens[0].token, Token::Identifier("foo".to_string()));
+        assert_eq!((tokens[0].span.line, tokens[0].span.column), (3, 1));
+    }
+
+    #[test]
+    fn invalid_number_is_an_error_token() {
+        let tokens: Vec<Token> = lex("1.2.3 + x")
+            .into_iter()
+            .map(|token| token.token)
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Error(LexError::InvalidNumber("1.2.3".to_string())),
+                Token::Plus,
+                Token::Identifier("x".to_string()),
+                Token::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn error_token_covers_the_bad_input() {
+        let tokens = lex("a 1.2.3 b");
+
+        assert_eq!((tokens[1].span.start, tokens[1].span.end), (2, 7));
+        assert_eq!(tokens[2].token, Token::Identifier("b".to_string()));
+        assert_eq!(tokens[2].span.column, 9);
+    }
+}
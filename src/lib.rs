@@ -0,0 +1,36 @@
+//! A JIT and ahead-of-time compiler for Kaleidoscope, the toy language of the LLVM tutorial.
+//!
+//! `Session` is the way in for embedding the language: it compiles source a piece at a time into
+//! the same program, and calls into it.
+//!
+//! ```
+//! use kaleidoscope::{Context, OptLevel, Session};
+//!
+//! let context = Context::new();
+//! let mut session = Session::new(&context, OptLevel::default(), None).unwrap();
+//! session.load_prelude().unwrap();
+//! session.register_fn("rate", |year: f64| 0.03 + year / 1000.0).unwrap();
+//!
+//! session.define("def grow(x years) x * pow(1 + rate(years), years);").unwrap();
+//! assert_eq!(session.eval("min(3, 4) + 1;").unwrap(), 4.0);
+//! let total = session.call("grow", &[100.0, 10.0]).unwrap();
+//! assert!((total - 100.0 * 1.04f64.powi(10)).abs() < 1e-9);
+//! ```
+
+#![feature(extern_types)]
+
+pub mod codegen;
+pub use codegen::{OptLevel, Target};
+
+pub mod diagnostics;
+pub mod host;
+pub mod lexer;
+pub mod parser;
+
+pub mod llvm;
+pub use llvm::Context;
+
+mod runtime;
+
+mod session;
+pub use session::{Error, Output, Session};
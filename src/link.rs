@@ -0,0 +1,99 @@
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::ErrorKind;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the builtins of `runtime.rs`, built for the host by build.rs
+const RUNTIME_ARCHIVE: &[u8] =
+    include_bytes!(concat!(env!("OUT_DIR"), "/libkaleidoscope_runtime.a"));
+
+/// A directory only this user can get at, for the files made along the way to an executable. It
+/// is removed along with everything in it when dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Result<Self, String> {
+        // creating the directory fails if anything is already there, so a name taken by someone
+        // else is never used, just tried again with another
+        for attempt in 0.. {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.subsec_nanos());
+            let path =
+                env::temp_dir().join(format!("kaleidoscope-{}-{:08x}", process::id(), nanos));
+
+            match DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => return Ok(TempDir { path }),
+                Err(err) if err.kind() == ErrorKind::AlreadyExists && attempt < 100 => continue,
+                Err(err) => {
+                    return Err(format!(
+                        "couldn't create a directory in {}: {}",
+                        env::temp_dir().display(),
+                        err
+                    ))
+                }
+            }
+        }
+
+        unreachable!()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+// calls the entry function and prints what it returns
+fn main_source(entry: &str) -> String {
+    format!(
+        "#include <stdio.h>\n\
+         \n\
+         double {entry}(void);\n\
+         \n\
+         int main(void) {{\n\
+         \x20   printf(\"%f\\n\", {entry}());\n\
+         \x20   return 0;\n\
+         }}\n",
+        entry = entry
+    )
+}
+
+/// Link an object file into an executable with the system C compiler (`$CC`, or `cc`), along with
+/// the runtime, the shared `libraries` and a `main` that calls `entry` and prints its result.
+pub fn link(object: &str, entry: &str, libraries: &[String], output: &str) -> Result<(), String> {
+    let dir = TempDir::new()?;
+    let main = dir.path().join("main.c");
+    fs::write(&main, main_source(entry))
+        .map_err(|err| format!("couldn't write {}: {}", main.display(), err))?;
+    let runtime = dir.path().join("libkaleidoscope-runtime.a");
+    fs::write(&runtime, RUNTIME_ARCHIVE)
+        .map_err(|err| format!("couldn't write {}: {}", runtime.display(), err))?;
+
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(&cc)
+        .arg(object)
+        .arg(&main)
+        .arg(&runtime)
+        .args(libraries)
+        .arg("-o")
+        .arg(output)
+        .arg("-lm")
+        .status();
+
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("`{}` failed: {}", cc, status)),
+        Err(err) => Err(format!("couldn't run `{}`: {}", cc, err)),
+    }
+}
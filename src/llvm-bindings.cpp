@@ -1,33 +1,99 @@
+#include <cstdlib>
+#include <cstring>
 #include <iostream>
 
 #include <llvm/ADT/APFloat.h>
+#include <llvm/ADT/StringMap.h>
+#include <llvm/ADT/Triple.h>
+#include <llvm/Bitcode/BitcodeWriter.h>
+#include <llvm/ExecutionEngine/JITEventListener.h>
+#include <llvm/ExecutionEngine/Orc/ExecutionUtils.h>
+#include <llvm/ExecutionEngine/Orc/LLJIT.h>
+#include <llvm/ExecutionEngine/Orc/RTDyldObjectLinkingLayer.h>
+#include <llvm/ExecutionEngine/Orc/ThreadSafeModule.h>
+#include <llvm/ExecutionEngine/SectionMemoryManager.h>
 #include <llvm/IR/Value.h>
 #include <llvm/IR/Constants.h>
+#include <llvm/IR/DIBuilder.h>
 #include <llvm/IR/IRBuilder.h>
 #include <llvm/IR/LLVMContext.h>
+#include <llvm/IR/LegacyPassManager.h>
 #include <llvm/IR/Module.h>
+#include <llvm/IR/Verifier.h>
+#include <llvm/MC/SubtargetFeature.h>
+#include <llvm/Pass.h>
+#include <llvm/Support/FileSystem.h>
+#include <llvm/Support/Host.h>
+#include <llvm/Support/TargetRegistry.h>
+#include <llvm/Support/TargetSelect.h>
+#include <llvm/Support/raw_ostream.h>
+#include <llvm/Target/TargetMachine.h>
+#include <llvm/Target/TargetOptions.h>
+#include <llvm/Transforms/IPO.h>
+#include <llvm/Transforms/InstCombine/InstCombine.h>
+#include <llvm/Transforms/Scalar.h>
+#include <llvm/Transforms/Scalar/GVN.h>
+#include <llvm/Transforms/Utils.h>
 
 using namespace llvm;
 
+// the DWARF debug info of one module, and the function it is being generated for
+struct DebugInfo {
+  DIBuilder builder;
+  DICompileUnit* unit;
+  DIFile* file;
+  DISubprogram* function = nullptr;
+
+  DebugInfo(Module& module) : builder(module) {}
+
+  DIType* double_type() {
+    return builder.createBasicType("double", 64, dwarf::DW_ATE_float);
+  }
+};
+
+// hand an error to Rust as a C string, to be freed with `dispose_message`
+static char* error_message(Error err) {
+  return strdup(toString(std::move(err)).c_str());
+}
+
 extern "C" {
+  void dispose_message(char* message) {
+    free(message);
+  }
+
   Value* get_constant_fp(LLVMContext* context, double value) {
     return ConstantFP::get(*context, APFloat(value));
   }
 
-  LLVMContext* get_context() {
-    return new LLVMContext();
+  // the context is shared with the JIT, which keeps it alive for as long as it holds modules
+  // created in it
+  orc::ThreadSafeContext* get_context() {
+    return new orc::ThreadSafeContext(std::make_unique<LLVMContext>());
+  }
+
+  // modules handed to the JIT keep the context alive until they're gone too
+  void delete_context(orc::ThreadSafeContext* context) {
+    delete context;
+  }
+
+  LLVMContext* context_get_llvm_context(orc::ThreadSafeContext* context) {
+    return context->getContext();
   }
 
   IRBuilder<>* get_builder(LLVMContext* context) {
     return new IRBuilder<>(*context);
   }
 
+  void delete_builder(IRBuilder<>* builder) {
+    delete builder;
+  }
+
   Module* get_module(LLVMContext* context) {
     return new Module("my cool jit", *context);
   }
 
-  void print_value(Value* value) {
-    value->print(outs(), true);
+  void delete_module(Module* module) {
+    delete module;
   }
 
   Value* builder_create_f_add(IRBuilder<>* builder, Value* lhs, Value* rhs, const char* op) {
@@ -42,16 +108,31 @@ extern "C" {
     return builder->CreateFMul(lhs, rhs, op);
   }
 
-  // TODO split this into two functions and call both from Rust
-  Value* builder_create_f_cmp_lt(
-      LLVMContext* context,
+  Value* builder_create_f_div(IRBuilder<>* builder, Value* lhs, Value* rhs, const char* op) {
+    return builder->CreateFDiv(lhs, rhs, op);
+  }
+
+  Value* builder_create_f_neg(IRBuilder<>* builder, Value* value, const char* op) {
+    return builder->CreateFNeg(value, op);
+  }
+
+  Value* builder_create_f_cmp(
       IRBuilder<>* builder,
+      unsigned predicate,
       Value* lhs,
       Value* rhs,
-      char const* op
+      const char* name
+  ) {
+    return builder->CreateFCmp((CmpInst::Predicate)predicate, lhs, rhs, name);
+  }
+
+  Value* builder_create_ui_to_fp(
+      LLVMContext* context,
+      IRBuilder<>* builder,
+      Value* value,
+      const char* name
   ) {
-    lhs = builder->CreateFCmpULT(lhs, rhs, "cmptmp");
-    return builder->CreateUIToFP(lhs, Type::getDoubleTy(*context), op);
+    return builder->CreateUIToFP(value, Type::getDoubleTy(*context), name);
   }
 
   Function* module_get_function(Module* module, const char* name) {
@@ -87,26 +168,579 @@ extern "C" {
     return F;
   }
 
+  // a new block at the end of `function`
+  BasicBlock* create_basic_block(LLVMContext* context, Function* function, const char* name) {
+    return BasicBlock::Create(*context, name, function);
+  }
+
+  void function_erase(Function* function) {
+    function->eraseFromParent();
+  }
+
+  void function_delete_body(Function* function) {
+    function->deleteBody();
+  }
+
+  void function_set_name(Function* function, const char* name) {
+    function->setName(name);
+  }
+
+  void function_set_available_externally(Function* function) {
+    function->setLinkage(GlobalValue::AvailableExternallyLinkage);
+  }
+
+  void function_set_internal(Function* function) {
+    function->setLinkage(GlobalValue::InternalLinkage);
+  }
+
+  bool function_is_used(Function* function) {
+    return !function->use_empty();
+  }
+
+  void basic_block_move_to_end(BasicBlock* block) {
+    BasicBlock* last = &block->getParent()->back();
+    if (last != block) {
+      block->moveAfter(last);
+    }
+  }
+
+  BasicBlock* builder_get_insert_block(IRBuilder<>* builder) {
+    return builder->GetInsertBlock();
+  }
+
+  void builder_set_insert_point(IRBuilder<>* builder, BasicBlock* block) {
+    builder->SetInsertPoint(block);
+  }
+
+  void builder_create_br(IRBuilder<>* builder, BasicBlock* dest) {
+    builder->CreateBr(dest);
+  }
+
+  void builder_create_cond_br(
+      IRBuilder<>* builder,
+      Value* cond,
+      BasicBlock* then_block,
+      BasicBlock* else_block
+  ) {
+    builder->CreateCondBr(cond, then_block, else_block);
+  }
+
+  PHINode* builder_create_phi(
+      LLVMContext* context,
+      IRBuilder<>* builder,
+      unsigned reserved_values,
+      const char* name
+  ) {
+    return builder->CreatePHI(Type::getDoubleTy(*context), reserved_values, name);
+  }
+
+  void phi_add_incoming(PHINode* phi, Value* value, BasicBlock* block) {
+    phi->addIncoming(value, block);
+  }
+
   void create_function_body(LLVMContext* context, Function* function, IRBuilder<>* builder) {
     BasicBlock* BB = BasicBlock::Create(*context, "entry", function);
     builder->SetInsertPoint(BB);
   }
 
-  // I'm going to leak ALL THE MEMORY
-  Value** get_function_args(Function* function) {
-    std::vector<Value*>* args = new std::vector<Value*>;
-    for (auto& arg : function->args()) {
-      args->push_back(&arg);
+  // allocas in the entry block are what mem2reg knows how to promote to registers
+  Value* create_entry_block_alloca(LLVMContext* context, Function* function, const char* name) {
+    IRBuilder<> builder(&function->getEntryBlock(), function->getEntryBlock().begin());
+    return builder.CreateAlloca(Type::getDoubleTy(*context), nullptr, name);
+  }
+
+  Value* builder_create_load(
+      LLVMContext* context,
+      IRBuilder<>* builder,
+      Value* ptr,
+      const char* name
+  ) {
+    return builder->CreateLoad(Type::getDoubleTy(*context), ptr, name);
+  }
+
+  void builder_create_store(IRBuilder<>* builder, Value* value, Value* ptr) {
+    builder->CreateStore(value, ptr);
+  }
+
+  // nothing runs at -O0, so the allocas of every variable are left alone
+  legacy::FunctionPassManager* create_function_pass_manager(Module* module, unsigned level) {
+    legacy::FunctionPassManager* fpm = new legacy::FunctionPassManager(module);
+    if (level > 0) {
+      fpm->add(createPromoteMemoryToRegisterPass());
+      fpm->add(createInstructionCombiningPass());
+      fpm->add(createReassociatePass());
+      fpm->add(createGVNPass());
+      fpm->add(createCFGSimplificationPass());
     }
+    fpm->doInitialization();
 
-    return args->data();
+    return fpm;
+  }
+
+  // inlining, then another round of cleanup over the inlined code
+  void run_module_passes(Module* module, unsigned level, unsigned size_level) {
+    if (level < 2) {
+      return;
+    }
+
+    legacy::PassManager pm;
+    pm.add(createFunctionInliningPass(level, size_level, false));
+    pm.add(createInstructionCombiningPass());
+    pm.add(createReassociatePass());
+    pm.add(createGVNPass());
+    pm.add(createCFGSimplificationPass());
+    pm.run(*module);
+  }
+
+  void function_pass_manager_run(legacy::FunctionPassManager* fpm, Function* function) {
+    fpm->run(*function);
+  }
+
+  size_t function_arg_size(Function* function) {
+    return function->arg_size();
+  }
+
+  Value* function_get_arg(Function* function, size_t index) {
+    return function->getArg(index);
   }
 
   void builder_create_ret(IRBuilder<>* builder, Value* value) {
     builder->CreateRet(value);
   }
 
-  void print_function(Function* function) {
-    function->print(outs(), nullptr, false, true);
+  // to be freed with `dispose_message`
+  char* function_to_string(Function* function) {
+    std::string ir;
+    raw_string_ostream out(ir);
+    function->print(out, nullptr, false, true);
+    return strdup(out.str().c_str());
+  }
+
+  // the functions the program can call without defining them: the builtins, and those of the
+  // process and of loaded libraries. The main JITDylib falls back to this one, so that a
+  // definition in the JIT takes the place of any of them.
+  orc::JITDylib& host_dylib(orc::LLJIT* jit) {
+    return *jit->getExecutionSession().getJITDylibByName("<host>");
+  }
+
+  // with `debug`, gdb is told about the code as it's compiled so that it can be debugged
+  orc::LLJIT* create_jit(bool debug, char** error) {
+    InitializeNativeTarget();
+    InitializeNativeTargetAsmPrinter();
+    InitializeNativeTargetAsmParser();
+
+    orc::LLJITBuilder builder;
+    if (debug) {
+      builder.setObjectLinkingLayerCreator([](orc::ExecutionSession& session, const Triple&) {
+        auto layer = std::make_unique<orc::RTDyldObjectLinkingLayer>(session, []() {
+          return std::make_unique<SectionMemoryManager>();
+        });
+        layer->registerJITEventListener(*JITEventListener::createGDBRegistrationListener());
+        return layer;
+      });
+    }
+
+    auto jit = builder.create();
+    if (!jit) {
+      *error = error_message(jit.takeError());
+      return nullptr;
+    }
+
+    orc::JITDylib& host = (*jit)->getExecutionSession().createBareJITDylib("<host>");
+    (*jit)->getMainJITDylib().addToLinkOrder(host);
+
+    // anything not defined in the JIT is looked up in the process, which has libc and libm in it
+    char prefix = (*jit)->getDataLayout().getGlobalPrefix();
+    auto process = orc::DynamicLibrarySearchGenerator::GetForCurrentProcess(prefix);
+    if (!process) {
+      *error = error_message(process.takeError());
+      return nullptr;
+    }
+    host.addGenerator(std::move(*process));
+
+    return jit->release();
+  }
+
+  // symbols the JIT can't find elsewhere are then looked up in the shared library at `path` too
+  bool jit_load_library(orc::LLJIT* jit, const char* path, char** error) {
+    char prefix = jit->getDataLayout().getGlobalPrefix();
+    auto library = orc::DynamicLibrarySearchGenerator::Load(path, prefix);
+    if (!library) {
+      *error = error_message(library.takeError());
+      return false;
+    }
+    host_dylib(jit).addGenerator(std::move(*library));
+
+    return true;
+  }
+
+  void delete_jit(orc::LLJIT* jit) {
+    delete jit;
+  }
+
+  void module_set_data_layout(Module* module, orc::LLJIT* jit) {
+    module->setDataLayout(jit->getDataLayout());
+  }
+
+  // takes ownership of the module. The returned tracker can be used to remove the module's code
+  // from the JIT again.
+  orc::ResourceTracker* jit_add_module(
+      orc::LLJIT* jit,
+      orc::ThreadSafeContext* context,
+      Module* module,
+      char** error
+  ) {
+    orc::ResourceTrackerSP tracker = jit->getMainJITDylib().createResourceTracker();
+    orc::ThreadSafeModule tsm(std::unique_ptr<Module>(module), *context);
+    if (Error err = jit->addIRModule(tracker, std::move(tsm))) {
+      *error = error_message(std::move(err));
+      return nullptr;
+    }
+
+    tracker->Retain();
+    return tracker.get();
+  }
+
+  // defines `name` in the JIT as a function of `arity` doubles that passes them on to
+  // `trampoline`, with `data` in front of them, for calling back into closures of the host program
+  orc::ResourceTracker* jit_add_trampoline(
+      orc::LLJIT* jit,
+      orc::ThreadSafeContext* context,
+      const char* name,
+      size_t arity,
+      uint64_t trampoline,
+      uint64_t data,
+      char** error
+  ) {
+    auto lock = context->getLock();
+    LLVMContext& llvm_context = *context->getContext();
+    auto module = std::make_unique<Module>(name, llvm_context);
+    module->setDataLayout(jit->getDataLayout());
+
+    Type* double_type = Type::getDoubleTy(llvm_context);
+    Type* data_type = Type::getInt8PtrTy(llvm_context);
+    std::vector<Type*> params(arity, double_type);
+    Function* function = Function::Create(
+        FunctionType::get(double_type, params, false),
+        Function::ExternalLinkage,
+        name,
+        module.get()
+    );
+
+    params.insert(params.begin(), data_type);
+    FunctionType* trampoline_type = FunctionType::get(double_type, params, false);
+    IRBuilder<> builder(BasicBlock::Create(llvm_context, "entry", function));
+    std::vector<Value*> args = {
+      ConstantExpr::getIntToPtr(builder.getInt64(data), data_type)
+    };
+    for (auto& arg : function->args()) {
+      args.push_back(&arg);
+    }
+    Constant* callee = ConstantExpr::getIntToPtr(
+        builder.getInt64(trampoline),
+        PointerType::getUnqual(trampoline_type)
+    );
+    builder.CreateRet(builder.CreateCall(trampoline_type, callee, args, "calltmp"));
+
+    orc::ResourceTrackerSP tracker = jit->getMainJITDylib().createResourceTracker();
+    orc::ThreadSafeModule tsm(std::move(module), *context);
+    if (Error err = jit->addIRModule(tracker, std::move(tsm))) {
+      *error = error_message(std::move(err));
+      return nullptr;
+    }
+
+    tracker->Retain();
+    return tracker.get();
+  }
+
+  // makes a function of the host program callable from JIT code under `name`, unless the JIT
+  // defines `name` itself
+  bool jit_define_symbol(orc::LLJIT* jit, const char* name, uint64_t address, char** error) {
+    orc::SymbolMap symbols;
+    symbols[jit->mangleAndIntern(name)] =
+        JITEvaluatedSymbol(address, JITSymbolFlags::Exported | JITSymbolFlags::Callable);
+    if (Error err = host_dylib(jit).define(orc::absoluteSymbols(std::move(symbols)))) {
+      *error = error_message(std::move(err));
+      return false;
+    }
+
+    return true;
+  }
+
+  uint64_t jit_lookup(orc::LLJIT* jit, const char* name, char** error) {
+    // in the JIT's code first, then where it falls back to
+    orc::JITDylibSearchOrder order;
+    jit->getMainJITDylib().withLinkOrderDo([&](const orc::JITDylibSearchOrder& link_order) {
+      order = link_order;
+    });
+    auto symbol = jit->getExecutionSession().lookup(order, jit->mangleAndIntern(name));
+    if (!symbol) {
+      *error = error_message(symbol.takeError());
+      return 0;
+    }
+
+    return symbol->getAddress();
+  }
+
+  bool resource_tracker_remove(orc::ResourceTracker* tracker, char** error) {
+    if (Error err = tracker->remove()) {
+      *error = error_message(std::move(err));
+      return false;
+    }
+
+    return true;
+  }
+
+  void resource_tracker_release(orc::ResourceTracker* tracker) {
+    tracker->Release();
+  }
+
+  void delete_function_pass_manager(legacy::FunctionPassManager* fpm) {
+    delete fpm;
+  }
+
+  // code for `triple`, or the machine we're running on when it's null. A null `cpu` or
+  // `features` means the host's for the host, and the target's defaults otherwise. The code is
+  // position independent so that it can be linked into the executables that C compilers make
+  // by default.
+  TargetMachine* create_target_machine(
+      const char* triple,
+      const char* cpu,
+      const char* features,
+      unsigned level,
+      char** error
+  ) {
+    InitializeAllTargetInfos();
+    InitializeAllTargets();
+    InitializeAllTargetMCs();
+    InitializeAllAsmPrinters();
+
+    std::string target_triple = triple
+      ? Triple::normalize(triple)
+      : sys::getDefaultTargetTriple();
+    std::string message;
+    const Target* target = TargetRegistry::lookupTarget(target_triple, message);
+    if (!target) {
+      *error = strdup(message.c_str());
+      return nullptr;
+    }
+
+    std::string target_cpu = cpu ? cpu : triple ? "" : sys::getHostCPUName().str();
+    std::string target_features;
+    if (features) {
+      target_features = features;
+    } else if (!triple) {
+      SubtargetFeatures host_features;
+      StringMap<bool> enabled;
+      if (sys::getHostCPUFeatures(enabled)) {
+        for (auto& feature : enabled) {
+          host_features.AddFeature(feature.first(), feature.second);
+        }
+      }
+      target_features = host_features.getString();
+    }
+
+    CodeGenOpt::Level opt_level = level == 0 ? CodeGenOpt::None
+      : level == 1 ? CodeGenOpt::Less
+      : level == 2 ? CodeGenOpt::Default
+      : CodeGenOpt::Aggressive;
+
+    return target->createTargetMachine(
+        target_triple,
+        target_cpu,
+        target_features,
+        TargetOptions(),
+        Reloc::PIC_,
+        None,
+        opt_level
+    );
+  }
+
+  // whether code for `triple` runs on the machine we're running on, whatever the vendor
+  bool triple_is_host(const char* triple) {
+    Triple target(Triple::normalize(triple));
+    Triple host(sys::getProcessTriple());
+    return target.getArch() == host.getArch()
+      && target.getOS() == host.getOS()
+      && target.getEnvironment() == host.getEnvironment();
+  }
+
+  void delete_target_machine(TargetMachine* target_machine) {
+    delete target_machine;
+  }
+
+  void module_set_target(Module* module, TargetMachine* target_machine) {
+    module->setDataLayout(target_machine->createDataLayout());
+    module->setTargetTriple(target_machine->getTargetTriple().str());
+  }
+
+  bool target_machine_emit_object(
+      TargetMachine* target_machine,
+      Module* module,
+      const char* path,
+      char** error
+  ) {
+    std::error_code ec;
+    raw_fd_ostream out(path, ec, sys::fs::OF_None);
+    if (ec) {
+      *error = strdup(ec.message().c_str());
+      return false;
+    }
+
+    legacy::PassManager pm;
+    if (target_machine->addPassesToEmitFile(pm, out, nullptr, CGFT_ObjectFile)) {
+      *error = strdup("the target can't emit object files");
+      return false;
+    }
+    pm.run(*module);
+    out.flush();
+
+    return true;
+  }
+
+  bool module_verify(Module* module, char** error) {
+    std::string message;
+    raw_string_ostream out(message);
+    if (verifyModule(*module, &out)) {
+      out.flush();
+      *error = strdup(message.c_str());
+      return false;
+    }
+
+    return true;
+  }
+
+  bool module_write_ir(Module* module, const char* path, char** error) {
+    std::error_code ec;
+    raw_fd_ostream out(path, ec, sys::fs::OF_Text);
+    if (ec) {
+      *error = strdup(ec.message().c_str());
+      return false;
+    }
+
+    module->print(out, nullptr);
+    out.flush();
+
+    return true;
+  }
+
+  bool module_write_bitcode(Module* module, const char* path, char** error) {
+    std::error_code ec;
+    raw_fd_ostream out(path, ec, sys::fs::OF_None);
+    if (ec) {
+      *error = strdup(ec.message().c_str());
+      return false;
+    }
+
+    WriteBitcodeToFile(*module, out);
+    out.flush();
+
+    return true;
+  }
+
+  DebugInfo* create_debug_info(
+      Module* module,
+      const char* file,
+      const char* directory,
+      bool optimized
+  ) {
+    module->addModuleFlag(Module::Warning, "Debug Info Version", DEBUG_METADATA_VERSION);
+    module->addModuleFlag(Module::Warning, "Dwarf Version", 4);
+
+    DebugInfo* debug_info = new DebugInfo(*module);
+    debug_info->file = debug_info->builder.createFile(file, directory);
+    debug_info->unit = debug_info->builder.createCompileUnit(
+        dwarf::DW_LANG_C,
+        debug_info->file,
+        "kaleidoscope",
+        optimized,
+        "",
+        0
+    );
+
+    return debug_info;
+  }
+
+  // must be called before the module is compiled or written out
+  void debug_info_finalize(DebugInfo* debug_info) {
+    debug_info->builder.finalize();
+  }
+
+  void delete_debug_info(DebugInfo* debug_info) {
+    delete debug_info;
+  }
+
+  void debug_info_begin_function(
+      DebugInfo* debug_info,
+      Function* function,
+      unsigned line,
+      unsigned scope_line
+  ) {
+    DIBuilder& builder = debug_info->builder;
+    SmallVector<Metadata*, 8> types(function->arg_size() + 1, debug_info->double_type());
+    DISubroutineType* type = builder.createSubroutineType(builder.getOrCreateTypeArray(types));
+
+    DISubprogram* subprogram = builder.createFunction(
+        debug_info->unit,
+        function->getName(),
+        StringRef(),
+        debug_info->file,
+        line,
+        type,
+        scope_line,
+        DINode::FlagPrototyped,
+        DISubprogram::SPFlagDefinition
+    );
+    function->setSubprogram(subprogram);
+    debug_info->function = subprogram;
+  }
+
+  // `index` counts from 1
+  void debug_info_declare_parameter(
+      DebugInfo* debug_info,
+      IRBuilder<>* builder,
+      Value* alloca,
+      const char* name,
+      unsigned index,
+      unsigned line,
+      unsigned column
+  ) {
+    DILocalVariable* variable = debug_info->builder.createParameterVariable(
+        debug_info->function,
+        name,
+        index,
+        debug_info->file,
+        line,
+        debug_info->double_type(),
+        true
+    );
+
+    debug_info->builder.insertDeclare(
+        alloca,
+        variable,
+        debug_info->builder.createExpression(),
+        DILocation::get(builder->getContext(), line, column, debug_info->function),
+        builder->GetInsertBlock()
+    );
+  }
+
+  void debug_info_set_location(
+      DebugInfo* debug_info,
+      IRBuilder<>* builder,
+      unsigned line,
+      unsigned column
+  ) {
+    builder->SetCurrentDebugLocation(
+        DILocation::get(builder->getContext(), line, column, debug_info->function)
+    );
+  }
+
+  DILocation* builder_get_debug_location(IRBuilder<>* builder) {
+    return builder->getCurrentDebugLocation().get();
+  }
+
+  // a null location means none
+  void builder_set_debug_location(IRBuilder<>* builder, DILocation* location) {
+    builder->SetCurrentDebugLocation(DebugLoc(location));
   }
 }
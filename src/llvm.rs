@@ -0,0 +1,1025 @@
+//! Owned, lifetime-checked handles over the C++ side in `llvm-bindings.cpp`.
+//!
+//! A `Context` owns everything LLVM allocates for the IR in it. `Module`, `Builder` and the other
+//! owned types free themselves when dropped. `Value`, `Function` and `BasicBlock` are plain
+//! handles into a module, which can't outlive the borrow of the `Module` they came from, so a
+//! module can't be dropped or given to the JIT while any are in use. Constants belong to the
+//! `Context` instead.
+
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::marker::PhantomData;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+/// How to compare two floats. Ordered comparisons are false when either side is NaN, unordered
+/// ones are true.
+// mirrors the FCMP_* values of llvm::CmpInst::Predicate
+#[allow(dead_code)]
+#[repr(u32)]
+#[derive(Debug, Clone, Copy)]
+pub enum FloatPredicate {
+    False = 0,
+    Oeq = 1,
+    Ogt = 2,
+    Oge = 3,
+    Olt = 4,
+    Ole = 5,
+    One = 6,
+    Ord = 7,
+    Uno = 8,
+    Ueq = 9,
+    Ugt = 10,
+    Uge = 11,
+    Ult = 12,
+    Ule = 13,
+    Une = 14,
+    True = 15,
+}
+
+mod sys {
+    use super::FloatPredicate;
+
+    extern "C" {
+        pub type Value;
+        pub type LlvmContext;
+        pub type IrBuilder;
+        pub type Module;
+        pub type Function;
+        pub type BasicBlock;
+        pub type PhiNode;
+        pub type FunctionPassManager;
+        pub type ThreadSafeContext;
+        pub type Jit;
+        pub type ResourceTracker;
+        pub type TargetMachine;
+        pub type DebugInfo;
+        pub type DebugLocation;
+
+        pub fn dispose_message(message: *mut i8);
+        pub fn get_context() -> *mut ThreadSafeContext;
+        pub fn delete_context(context: *mut ThreadSafeContext);
+        pub fn context_get_llvm_context(context: *mut ThreadSafeContext) -> *mut LlvmContext;
+        pub fn get_builder(context: *mut LlvmContext) -> *mut IrBuilder;
+        pub fn delete_builder(builder: *mut IrBuilder);
+        pub fn get_module(context: *mut LlvmContext) -> *mut Module;
+        pub fn delete_module(module: *mut Module);
+        pub fn get_constant_fp(context: *mut LlvmContext, value: f64) -> *mut Value;
+        pub fn function_to_string(function: *mut Function) -> *mut i8;
+        pub fn builder_create_f_add(
+            builder: *mut IrBuilder,
+            lhs: *mut Value,
+            rhs: *mut Value,
+            op: *const i8,
+        ) -> *mut Value;
+        pub fn builder_create_f_sub(
+            builder: *mut IrBuilder,
+            lhs: *mut Value,
+            rhs: *mut Value,
+            op: *const i8,
+        ) -> *mut Value;
+        pub fn builder_create_f_mul(
+            builder: *mut IrBuilder,
+            lhs: *mut Value,
+            rhs: *mut Value,
+            op: *const i8,
+        ) -> *mut Value;
+        pub fn builder_create_f_div(
+            builder: *mut IrBuilder,
+            lhs: *mut Value,
+            rhs: *mut Value,
+            op: *const i8,
+        ) -> *mut Value;
+        pub fn builder_create_f_neg(
+            builder: *mut IrBuilder,
+            value: *mut Value,
+            op: *const i8,
+        ) -> *mut Value;
+        pub fn builder_create_f_cmp(
+            builder: *mut IrBuilder,
+            predicate: FloatPredicate,
+            lhs: *mut Value,
+            rhs: *mut Value,
+            name: *const i8,
+        ) -> *mut Value;
+        pub fn builder_create_ui_to_fp(
+            context: *mut LlvmContext,
+            builder: *mut IrBuilder,
+            value: *mut Value,
+            name: *const i8,
+        ) -> *mut Value;
+        pub fn module_get_function(module: *mut Module, name: *const i8) -> *mut Function;
+        pub fn builder_create_call(
+            builder: *mut IrBuilder,
+            function: *mut Function,
+            arg_buf: *mut *mut Value,
+            arg_size: usize,
+            name: *const i8,
+        ) -> *mut Value;
+        pub fn module_create_function(
+            context: *mut LlvmContext,
+            module: *mut Module,
+            name: *const i8,
+            args: *mut *const i8,
+            args_size: usize,
+        ) -> *mut Function;
+        pub fn create_basic_block(
+            context: *mut LlvmContext,
+            function: *mut Function,
+            name: *const i8,
+        ) -> *mut BasicBlock;
+        pub fn function_erase(function: *mut Function);
+        pub fn function_delete_body(function: *mut Function);
+        pub fn function_set_available_externally(function: *mut Function);
+        pub fn function_set_internal(function: *mut Function);
+        pub fn function_is_used(function: *mut Function) -> bool;
+        pub fn function_set_name(function: *mut Function, name: *const i8);
+        pub fn basic_block_move_to_end(block: *mut BasicBlock);
+        pub fn builder_get_insert_block(builder: *mut IrBuilder) -> *mut BasicBlock;
+        pub fn builder_set_insert_point(builder: *mut IrBuilder, block: *mut BasicBlock);
+        pub fn builder_create_br(builder: *mut IrBuilder, dest: *mut BasicBlock);
+        pub fn builder_create_cond_br(
+            builder: *mut IrBuilder,
+            cond: *mut Value,
+            then_block: *mut BasicBlock,
+            else_block: *mut BasicBlock,
+        );
+        pub fn builder_create_phi(
+            context: *mut LlvmContext,
+            builder: *mut IrBuilder,
+            reserved_values: u32,
+            name: *const i8,
+        ) -> *mut PhiNode;
+        pub fn phi_add_incoming(phi: *mut PhiNode, value: *mut Value, block: *mut BasicBlock);
+        pub fn create_function_body(
+            context: *mut LlvmContext,
+            function: *mut Function,
+            builder: *mut IrBuilder,
+        );
+        pub fn create_entry_block_alloca(
+            context: *mut LlvmContext,
+            function: *mut Function,
+            name: *const i8,
+        ) -> *mut Value;
+        pub fn builder_create_load(
+            context: *mut LlvmContext,
+            builder: *mut IrBuilder,
+            ptr: *mut Value,
+            name: *const i8,
+        ) -> *mut Value;
+        pub fn builder_create_store(builder: *mut IrBuilder, value: *mut Value, ptr: *mut Value);
+        pub fn create_function_pass_manager(
+            module: *mut Module,
+            level: u32,
+        ) -> *mut FunctionPassManager;
+        pub fn run_module_passes(module: *mut Module, level: u32, size_level: u32);
+        pub fn function_pass_manager_run(fpm: *mut FunctionPassManager, function: *mut Function);
+        pub fn delete_function_pass_manager(fpm: *mut FunctionPassManager);
+        pub fn create_jit(debug: bool, error: *mut *mut i8) -> *mut Jit;
+        pub fn delete_jit(jit: *mut Jit);
+        pub fn module_set_data_layout(module: *mut Module, jit: *mut Jit);
+        pub fn jit_add_module(
+            jit: *mut Jit,
+            context: *mut ThreadSafeContext,
+            module: *mut Module,
+            error: *mut *mut i8,
+        ) -> *mut ResourceTracker;
+        pub fn jit_add_trampoline(
+            jit: *mut Jit,
+            context: *mut ThreadSafeContext,
+            name: *const i8,
+            arity: usize,
+            trampoline: u64,
+            data: u64,
+            error: *mut *mut i8,
+        ) -> *mut ResourceTracker;
+        pub fn jit_load_library(jit: *mut Jit, path: *const i8, error: *mut *mut i8) -> bool;
+        pub fn jit_define_symbol(
+            jit: *mut Jit,
+            name: *const i8,
+            address: u64,
+            error: *mut *mut i8,
+        ) -> bool;
+        pub fn jit_lookup(jit: *mut Jit, name: *const i8, error: *mut *mut i8) -> u64;
+        pub fn resource_tracker_remove(tracker: *mut ResourceTracker, error: *mut *mut i8) -> bool;
+        pub fn resource_tracker_release(tracker: *mut ResourceTracker);
+        pub fn create_target_machine(
+            triple: *const i8,
+            cpu: *const i8,
+            features: *const i8,
+            level: u32,
+            error: *mut *mut i8,
+        ) -> *mut TargetMachine;
+        pub fn triple_is_host(triple: *const i8) -> bool;
+        pub fn delete_target_machine(target_machine: *mut TargetMachine);
+        pub fn module_set_target(module: *mut Module, target_machine: *mut TargetMachine);
+        pub fn target_machine_emit_object(
+            target_machine: *mut TargetMachine,
+            module: *mut Module,
+            path: *const i8,
+            error: *mut *mut i8,
+        ) -> bool;
+        pub fn module_verify(module: *mut Module, error: *mut *mut i8) -> bool;
+        pub fn module_write_ir(module: *mut Module, path: *const i8, error: *mut *mut i8) -> bool;
+        pub fn module_write_bitcode(
+            module: *mut Module,
+            path: *const i8,
+            error: *mut *mut i8,
+        ) -> bool;
+        pub fn create_debug_info(
+            module: *mut Module,
+            file: *const i8,
+            directory: *const i8,
+            optimized: bool,
+        ) -> *mut DebugInfo;
+        pub fn debug_info_finalize(debug_info: *mut DebugInfo);
+        pub fn delete_debug_info(debug_info: *mut DebugInfo);
+        pub fn debug_info_begin_function(
+            debug_info: *mut DebugInfo,
+            function: *mut Function,
+            line: u32,
+            scope_line: u32,
+        );
+        pub fn debug_info_declare_parameter(
+            debug_info: *mut DebugInfo,
+            builder: *mut IrBuilder,
+            alloca: *mut Value,
+            name: *const i8,
+            index: u32,
+            line: u32,
+            column: u32,
+        );
+        pub fn debug_info_set_location(
+            debug_info: *mut DebugInfo,
+            builder: *mut IrBuilder,
+            line: u32,
+            column: u32,
+        );
+        pub fn builder_get_debug_location(builder: *mut IrBuilder) -> *mut DebugLocation;
+        pub fn builder_set_debug_location(builder: *mut IrBuilder, location: *mut DebugLocation);
+        pub fn function_arg_size(function: *mut Function) -> usize;
+        pub fn function_get_arg(function: *mut Function, index: usize) -> *mut Value;
+        pub fn builder_create_ret(builder: *mut IrBuilder, value: *mut Value);
+    }
+}
+
+// take ownership of an error message or other string handed over by the C++ side
+unsafe fn take_message(message: *mut i8) -> String {
+    let s = CStr::from_ptr(message).to_string_lossy().into_owned();
+    sys::dispose_message(message);
+
+    s
+}
+
+// for the C++ functions that report failure by returning false
+unsafe fn check(f: impl FnOnce(*mut *mut i8) -> bool) -> Result<(), String> {
+    let mut error = ptr::null_mut();
+    if f(&mut error) {
+        Ok(())
+    } else {
+        Err(take_message(error))
+    }
+}
+
+/// Owns all the types, constants and other IR that modules are built from.
+pub struct Context {
+    inner: *mut sys::ThreadSafeContext,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Context {
+            inner: unsafe { sys::get_context() },
+        }
+    }
+
+    fn llvm_context(&self) -> *mut sys::LlvmContext {
+        unsafe { sys::context_get_llvm_context(self.inner) }
+    }
+
+    pub fn create_module(&self) -> Module<'_> {
+        Module {
+            inner: unsafe { sys::get_module(self.llvm_context()) },
+            _context: PhantomData,
+        }
+    }
+
+    pub fn create_builder(&self) -> Builder<'_> {
+        Builder {
+            inner: unsafe { sys::get_builder(self.llvm_context()) },
+            context: self,
+            _module: PhantomData,
+        }
+    }
+
+    pub fn const_f64(&self, value: f64) -> Value<'_> {
+        Value::new(unsafe { sys::get_constant_fp(self.llvm_context(), value) })
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe { sys::delete_context(self.inner) }
+    }
+}
+
+/// An instruction, argument or function of a module borrowed for `'m`, or a constant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Value<'m> {
+    inner: *mut sys::Value,
+    _module: PhantomData<&'m Module<'m>>,
+}
+
+impl<'m> Value<'m> {
+    fn new(inner: *mut sys::Value) -> Self {
+        Value {
+            inner,
+            _module: PhantomData,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Function<'m> {
+    inner: *mut sys::Function,
+    _module: PhantomData<&'m Module<'m>>,
+}
+
+impl<'m> Function<'m> {
+    fn new(inner: *mut sys::Function) -> Option<Self> {
+        if inner.is_null() {
+            return None;
+        }
+
+        Some(Function {
+            inner,
+            _module: PhantomData,
+        })
+    }
+
+    pub fn args(self) -> Vec<Value<'m>> {
+        unsafe {
+            (0..sys::function_arg_size(self.inner))
+                .map(|index| Value::new(sys::function_get_arg(self.inner, index)))
+                .collect()
+        }
+    }
+
+    /// A new block at the end of the function.
+    pub fn append_basic_block(self, context: &Context, name: &str) -> BasicBlock<'m> {
+        let name = CString::new(name).unwrap();
+        BasicBlock {
+            inner: unsafe {
+                sys::create_basic_block(context.llvm_context(), self.inner, name.as_ptr())
+            },
+            _module: PhantomData,
+        }
+    }
+
+    /// Remove the function from its module and free it.
+    ///
+    /// # Safety
+    ///
+    /// Nothing in the function may be used afterwards: not this handle or its copies, nor the
+    /// handles of its blocks, arguments and instructions, nor a builder inserting into it.
+    pub unsafe fn erase(self) {
+        sys::function_erase(self.inner)
+    }
+
+    /// Free the function's body, leaving a declaration that its calls still refer to.
+    ///
+    /// # Safety
+    ///
+    /// Nothing in the body may be used afterwards: not the handles of its blocks and
+    /// instructions, nor a builder inserting into it.
+    pub unsafe fn delete_body(self) {
+        sys::function_delete_body(self.inner)
+    }
+
+    /// Rename the function, which its calls follow.
+    pub fn set_name(self, name: &str) {
+        let name = CString::new(name).unwrap();
+        unsafe { sys::function_set_name(self.inner, name.as_ptr()) }
+    }
+
+    /// Mark the function as a copy of one defined elsewhere, which optimizations can look into
+    /// but which isn't emitted.
+    pub fn set_available_externally(self) {
+        unsafe { sys::function_set_available_externally(self.inner) }
+    }
+
+    /// Keep the function out of the symbols the module exports.
+    pub fn set_internal(self) {
+        unsafe { sys::function_set_internal(self.inner) }
+    }
+
+    /// Whether anything in the module, like a call, refers to the function.
+    pub fn is_used(self) -> bool {
+        unsafe { sys::function_is_used(self.inner) }
+    }
+
+    /// The function's IR, as text.
+    pub fn to_ir(self) -> String {
+        unsafe { take_message(sys::function_to_string(self.inner)) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BasicBlock<'m> {
+    inner: *mut sys::BasicBlock,
+    _module: PhantomData<&'m Module<'m>>,
+}
+
+impl<'m> BasicBlock<'m> {
+    /// Move the block after all the others of its function, so that blocks can be laid out in
+    /// the order they're filled in.
+    pub fn move_to_end(self) {
+        unsafe { sys::basic_block_move_to_end(self.inner) }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Phi<'m> {
+    inner: *mut sys::PhiNode,
+    _module: PhantomData<&'m Module<'m>>,
+}
+
+impl<'m> Phi<'m> {
+    pub fn add_incoming(self, value: Value<'m>, block: BasicBlock<'m>) {
+        unsafe { sys::phi_add_incoming(self.inner, value.inner, block.inner) }
+    }
+
+    pub fn as_value(self) -> Value<'m> {
+        Value::new(self.inner as *mut sys::Value)
+    }
+}
+
+/// Where the instructions a builder makes come from in the source, when generating debug info.
+/// It may be no location at all.
+#[derive(Debug, Clone, Copy)]
+pub struct DebugLocation<'m> {
+    inner: *mut sys::DebugLocation,
+    _module: PhantomData<&'m Module<'m>>,
+}
+
+pub struct Module<'ctx> {
+    inner: *mut sys::Module,
+    _context: PhantomData<&'ctx Context>,
+}
+
+impl<'ctx> Module<'ctx> {
+    pub fn get_function(&self, name: &str) -> Option<Function<'_>> {
+        let name = CString::new(name).unwrap();
+        Function::new(unsafe { sys::module_get_function(self.inner, name.as_ptr()) })
+    }
+
+    /// Declare a function taking `args` doubles and returning a double.
+    pub fn create_function(&self, context: &Context, name: &str, args: &[String]) -> Function<'_> {
+        let name = CString::new(name).unwrap();
+        let args: Vec<CString> = args
+            .iter()
+            .map(|s| CString::new(s.as_str()).unwrap())
+            .collect();
+        let mut args: Vec<*const i8> = args.iter().map(|s| s.as_ptr()).collect();
+
+        let function = unsafe {
+            sys::module_create_function(
+                context.llvm_context(),
+                self.inner,
+                name.as_ptr(),
+                args.as_mut_ptr(),
+                args.len(),
+            )
+        };
+        Function::new(function).unwrap()
+    }
+
+    pub fn set_data_layout(&self, jit: &Jit) {
+        unsafe { sys::module_set_data_layout(self.inner, jit.inner) }
+    }
+
+    pub fn set_target(&self, target_machine: &TargetMachine) {
+        unsafe { sys::module_set_target(self.inner, target_machine.inner) }
+    }
+
+    /// Inlining and cleanup across the functions of the module, at `level` 2 and up.
+    pub fn run_passes(&self, level: u32, size_level: u32) {
+        unsafe { sys::run_module_passes(self.inner, level, size_level) }
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        unsafe { check(|error| sys::module_verify(self.inner, error)) }
+    }
+
+    /// Write the module as textual IR, as in a `.ll` file.
+    pub fn write_ir(&self, path: &str) -> Result<(), String> {
+        let path = CString::new(path).unwrap();
+        unsafe { check(|error| sys::module_write_ir(self.inner, path.as_ptr(), error)) }
+    }
+
+    /// Write the module as bitcode, as in a `.bc` file.
+    pub fn write_bitcode(&self, path: &str) -> Result<(), String> {
+        let path = CString::new(path).unwrap();
+        unsafe { check(|error| sys::module_write_bitcode(self.inner, path.as_ptr(), error)) }
+    }
+
+    // give up ownership, to the JIT
+    fn into_raw(self) -> *mut sys::Module {
+        let inner = self.inner;
+        mem::forget(self);
+
+        inner
+    }
+}
+
+impl Drop for Module<'_> {
+    fn drop(&mut self) {
+        unsafe { sys::delete_module(self.inner) }
+    }
+}
+
+/// Inserts instructions into the functions of a module borrowed for `'m`. Unlike the handles, a
+/// builder can't be used for a shorter borrow than the one it was made for, since it keeps the
+/// place it was last told to insert at.
+pub struct Builder<'m> {
+    inner: *mut sys::IrBuilder,
+    context: &'m Context,
+    _module: PhantomData<Cell<&'m Module<'m>>>,
+}
+
+impl<'m> Builder<'m> {
+    pub fn create_f_add(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = CString::new(name).unwrap();
+        Value::new(unsafe {
+            sys::builder_create_f_add(self.inner, lhs.inner, rhs.inner, name.as_ptr())
+        })
+    }
+
+    pub fn create_f_sub(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = CString::new(name).unwrap();
+        Value::new(unsafe {
+            sys::builder_create_f_sub(self.inner, lhs.inner, rhs.inner, name.as_ptr())
+        })
+    }
+
+    pub fn create_f_mul(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = CString::new(name).unwrap();
+        Value::new(unsafe {
+            sys::builder_create_f_mul(self.inner, lhs.inner, rhs.inner, name.as_ptr())
+        })
+    }
+
+    pub fn create_f_div(&self, lhs: Value<'m>, rhs: Value<'m>, name: &str) -> Value<'m> {
+        let name = CString::new(name).unwrap();
+        Value::new(unsafe {
+            sys::builder_create_f_div(self.inner, lhs.inner, rhs.inner, name.as_ptr())
+        })
+    }
+
+    pub fn create_f_neg(&self, value: Value<'m>, name: &str) -> Value<'m> {
+        let name = CString::new(name).unwrap();
+        Value::new(unsafe { sys::builder_create_f_neg(self.inner, value.inner, name.as_ptr()) })
+    }
+
+    pub fn create_f_cmp(
+        &self,
+        predicate: FloatPredicate,
+        lhs: Value<'m>,
+        rhs: Value<'m>,
+        name: &str,
+    ) -> Value<'m> {
+        let name = CString::new(name).unwrap();
+        Value::new(unsafe {
+            sys::builder_create_f_cmp(self.inner, predicate, lhs.inner, rhs.inner, name.as_ptr())
+        })
+    }
+
+    pub fn create_ui_to_fp(&self, value: Value<'m>, name: &str) -> Value<'m> {
+        let name = CString::new(name).unwrap();
+        Value::new(unsafe {
+            sys::builder_create_ui_to_fp(
+                self.context.llvm_context(),
+                self.inner,
+                value.inner,
+                name.as_ptr(),
+            )
+        })
+    }
+
+    /// Give `function` an entry block and start inserting there.
+    pub fn create_function_body(&self, function: Function<'m>) {
+        unsafe {
+            sys::create_function_body(self.context.llvm_context(), function.inner, self.inner)
+        }
+    }
+
+    /// A stack slot in the entry block of `function`, which is what mem2reg knows how to promote
+    /// to a register.
+    pub fn create_entry_block_alloca(&self, function: Function<'m>, name: &str) -> Value<'m> {
+        let name = CString::new(name).unwrap();
+        Value::new(unsafe {
+            sys::create_entry_block_alloca(
+                self.context.llvm_context(),
+                function.inner,
+                name.as_ptr(),
+            )
+        })
+    }
+
+    pub fn get_insert_block(&self) -> BasicBlock<'m> {
+        let inner = unsafe { sys::builder_get_insert_block(self.inner) };
+        assert!(!inner.is_null(), "builder isn't inserting into a block");
+
+        BasicBlock {
+            inner,
+            _module: PhantomData,
+        }
+    }
+
+    pub fn set_insert_point(&self, block: BasicBlock<'m>) {
+        unsafe { sys::builder_set_insert_point(self.inner, block.inner) }
+    }
+
+    pub fn create_br(&self, dest: BasicBlock<'m>) {
+        unsafe { sys::builder_create_br(self.inner, dest.inner) }
+    }
+
+    pub fn create_cond_br(
+        &self,
+        cond: Value<'m>,
+        then_block: BasicBlock<'m>,
+        else_block: BasicBlock<'m>,
+    ) {
+        unsafe {
+            sys::builder_create_cond_br(self.inner, cond.inner, then_block.inner, else_block.inner)
+        }
+    }
+
+    pub fn create_phi(&self, reserved_values: u32, name: &str) -> Phi<'m> {
+        let name = CString::new(name).unwrap();
+        Phi {
+            inner: unsafe {
+                sys::builder_create_phi(
+                    self.context.llvm_context(),
+                    self.inner,
+                    reserved_values,
+                    name.as_ptr(),
+                )
+            },
+            _module: PhantomData,
+        }
+    }
+
+    pub fn create_load(&self, ptr: Value<'m>, name: &str) -> Value<'m> {
+        let name = CString::new(name).unwrap();
+        Value::new(unsafe {
+            sys::builder_create_load(
+                self.context.llvm_context(),
+                self.inner,
+                ptr.inner,
+                name.as_ptr(),
+            )
+        })
+    }
+
+    pub fn create_store(&self, value: Value<'m>, ptr: Value<'m>) {
+        unsafe { sys::builder_create_store(self.inner, value.inner, ptr.inner) }
+    }
+
+    pub fn create_call(&self, function: Function<'m>, args: &[Value<'m>]) -> Value<'m> {
+        let name = CString::new("calltmp").unwrap();
+        let mut args: Vec<*mut sys::Value> = args.iter().map(|arg| arg.inner).collect();
+        Value::new(unsafe {
+            sys::builder_create_call(
+                self.inner,
+                function.inner,
+                args.as_mut_ptr(),
+                args.len(),
+                name.as_ptr(),
+            )
+        })
+    }
+
+    pub fn create_ret(&self, value: Value<'m>) {
+        unsafe { sys::builder_create_ret(self.inner, value.inner) }
+    }
+
+    pub fn debug_location(&self) -> DebugLocation<'m> {
+        DebugLocation {
+            inner: unsafe { sys::builder_get_debug_location(self.inner) },
+            _module: PhantomData,
+        }
+    }
+
+    pub fn set_debug_location(&self, location: DebugLocation<'m>) {
+        unsafe { sys::builder_set_debug_location(self.inner, location.inner) }
+    }
+}
+
+impl Drop for Builder<'_> {
+    fn drop(&mut self) {
+        unsafe { sys::delete_builder(self.inner) }
+    }
+}
+
+/// The optimizations run on each function as soon as it's compiled.
+pub struct FunctionPassManager<'ctx> {
+    inner: *mut sys::FunctionPassManager,
+    _context: PhantomData<&'ctx Context>,
+}
+
+impl<'ctx> FunctionPassManager<'ctx> {
+    /// Nothing runs at `level` 0.
+    ///
+    /// # Safety
+    ///
+    /// The pass manager must be dropped before `module`, and only run on its functions.
+    pub unsafe fn new(module: &Module<'ctx>, level: u32) -> Self {
+        FunctionPassManager {
+            inner: sys::create_function_pass_manager(module.inner, level),
+            _context: PhantomData,
+        }
+    }
+
+    pub fn run(&self, function: Function<'_>) {
+        unsafe { sys::function_pass_manager_run(self.inner, function.inner) }
+    }
+}
+
+impl Drop for FunctionPassManager<'_> {
+    fn drop(&mut self) {
+        unsafe { sys::delete_function_pass_manager(self.inner) }
+    }
+}
+
+pub struct Jit {
+    inner: *mut sys::Jit,
+    owner: Rc<JitOwner>,
+}
+
+// frees the JIT once neither the `Jit` nor any tracker of code in it is left
+struct JitOwner(*mut sys::Jit);
+
+impl Drop for JitOwner {
+    fn drop(&mut self) {
+        unsafe { sys::delete_jit(self.0) }
+    }
+}
+
+impl Jit {
+    /// Functions that aren't defined in the JIT are looked up in the running process. With
+    /// `debug`, gdb is told about the code as it's compiled.
+    pub fn new(debug: bool) -> Result<Self, String> {
+        unsafe {
+            let mut error = ptr::null_mut();
+            let inner = sys::create_jit(debug, &mut error);
+            if inner.is_null() {
+                return Err(take_message(error));
+            }
+
+            Ok(Jit {
+                inner,
+                owner: Rc::new(JitOwner(inner)),
+            })
+        }
+    }
+
+    /// The module belongs to the JIT from here on.
+    pub fn add_module(&self, context: &Context, module: Module) -> Result<ResourceTracker, String> {
+        unsafe {
+            let mut error = ptr::null_mut();
+            let inner =
+                sys::jit_add_module(self.inner, context.inner, module.into_raw(), &mut error);
+            if inner.is_null() {
+                return Err(take_message(error));
+            }
+
+            Ok(ResourceTracker {
+                inner,
+                _jit: Rc::clone(&self.owner),
+            })
+        }
+    }
+
+    /// Define `name` as a function of `arity` doubles that calls the C function at `trampoline`
+    /// with `data` as its first argument, followed by the doubles.
+    pub fn add_trampoline(
+        &self,
+        context: &Context,
+        name: &str,
+        arity: usize,
+        trampoline: u64,
+        data: u64,
+    ) -> Result<ResourceTracker, String> {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            let mut error = ptr::null_mut();
+            let inner = sys::jit_add_trampoline(
+                self.inner,
+                context.inner,
+                name.as_ptr(),
+                arity,
+                trampoline,
+                data,
+                &mut error,
+            );
+            if inner.is_null() {
+                return Err(take_message(error));
+            }
+
+            Ok(ResourceTracker {
+                inner,
+                _jit: Rc::clone(&self.owner),
+            })
+        }
+    }
+
+    /// Look up whatever the JIT can't find elsewhere in the shared library at `path` too.
+    pub fn load_library(&self, path: &str) -> Result<(), String> {
+        let path = CString::new(path).unwrap();
+        unsafe { check(|error| sys::jit_load_library(self.inner, path.as_ptr(), error)) }
+    }
+
+    /// Make the function at `address` callable from the code in the JIT as `name`, unless code
+    /// added to the JIT defines `name` itself.
+    pub fn define_symbol(&self, name: &str, address: u64) -> Result<(), String> {
+        let name = CString::new(name).unwrap();
+        unsafe { check(|error| sys::jit_define_symbol(self.inner, name.as_ptr(), address, error)) }
+    }
+
+    pub fn lookup(&self, name: &str) -> Result<u64, String> {
+        unsafe {
+            let s = CString::new(name).unwrap();
+            let mut error = ptr::null_mut();
+            let address = sys::jit_lookup(self.inner, s.as_ptr(), &mut error);
+            if address == 0 {
+                return Err(take_message(error));
+            }
+
+            Ok(address)
+        }
+    }
+}
+
+/// The code of one module added to the JIT. Dropping the tracker keeps the code around, `remove`
+/// takes it out of the JIT. The JIT isn't freed before its trackers are.
+pub struct ResourceTracker {
+    inner: *mut sys::ResourceTracker,
+    _jit: Rc<JitOwner>,
+}
+
+impl ResourceTracker {
+    pub fn remove(self) -> Result<(), String> {
+        unsafe { check(|error| sys::resource_tracker_remove(self.inner, error)) }
+    }
+}
+
+impl Drop for ResourceTracker {
+    fn drop(&mut self) {
+        unsafe { sys::resource_tracker_release(self.inner) }
+    }
+}
+
+/// Generates machine code for one target, for compiling ahead of time.
+pub struct TargetMachine {
+    inner: *mut sys::TargetMachine,
+}
+
+impl TargetMachine {
+    /// Without a `triple` the target is the machine we're running on. A missing `cpu` or
+    /// `features` is then the host's, and the target's default when cross-compiling.
+    pub fn new(
+        triple: Option<&str>,
+        cpu: Option<&str>,
+        features: Option<&str>,
+        level: u32,
+    ) -> Result<Self, String> {
+        let c_string = |s: Option<&str>| s.map(|s| CString::new(s).unwrap());
+        let as_ptr = |s: &Option<CString>| s.as_ref().map_or(ptr::null(), |s| s.as_ptr());
+        let (triple, cpu, features) = (c_string(triple), c_string(cpu), c_string(features));
+
+        unsafe {
+            let mut error = ptr::null_mut();
+            let inner = sys::create_target_machine(
+                as_ptr(&triple),
+                as_ptr(&cpu),
+                as_ptr(&features),
+                level,
+                &mut error,
+            );
+            if inner.is_null() {
+                return Err(take_message(error));
+            }
+
+            Ok(TargetMachine { inner })
+        }
+    }
+
+    /// Whether code for `triple` runs on the machine we're running on.
+    pub fn is_host(triple: &str) -> bool {
+        let triple = CString::new(triple).unwrap();
+        unsafe { sys::triple_is_host(triple.as_ptr()) }
+    }
+
+    pub fn emit_object(&self, module: &Module, path: &str) -> Result<(), String> {
+        let path = CString::new(path).unwrap();
+        unsafe {
+            check(|error| {
+                sys::target_machine_emit_object(self.inner, module.inner, path.as_ptr(), error)
+            })
+        }
+    }
+}
+
+impl Drop for TargetMachine {
+    fn drop(&mut self) {
+        unsafe { sys::delete_target_machine(self.inner) }
+    }
+}
+
+/// The DWARF debug info of one module. It has to be finalized before the module is compiled or
+/// written out.
+pub struct DebugInfo<'ctx> {
+    inner: *mut sys::DebugInfo,
+    _context: PhantomData<&'ctx Context>,
+}
+
+impl<'ctx> DebugInfo<'ctx> {
+    /// # Safety
+    ///
+    /// The debug info must be dropped before `module`, and only used for its functions.
+    pub unsafe fn new(module: &Module<'ctx>, file: &str, directory: &str, optimized: bool) -> Self {
+        let file = CString::new(file).unwrap();
+        let directory = CString::new(directory).unwrap();
+
+        DebugInfo {
+            inner: sys::create_debug_info(
+                module.inner,
+                file.as_ptr(),
+                directory.as_ptr(),
+                optimized,
+            ),
+            _context: PhantomData,
+        }
+    }
+
+    /// Locations set from here on are inside `function`.
+    pub fn begin_function(&self, function: Function<'_>, line: usize, scope_line: usize) {
+        unsafe {
+            sys::debug_info_begin_function(
+                self.inner,
+                function.inner,
+                line as u32,
+                scope_line as u32,
+            )
+        }
+    }
+
+    /// `index` counts from 0.
+    pub fn declare_parameter<'m>(
+        &self,
+        builder: &Builder<'m>,
+        variable: Value<'m>,
+        name: &str,
+        index: usize,
+        line: usize,
+        column: usize,
+    ) {
+        let name = CString::new(name).unwrap();
+
+        unsafe {
+            sys::debug_info_declare_parameter(
+                self.inner,
+                builder.inner,
+                variable.inner,
+                name.as_ptr(),
+                index as u32 + 1,
+                line as u32,
+                column as u32,
+            )
+        }
+    }
+
+    pub fn set_location(&self, builder: &Builder<'_>, line: usize, column: usize) {
+        unsafe {
+            sys::debug_info_set_location(self.inner, builder.inner, line as u32, column as u32)
+        }
+    }
+
+    pub fn finalize(self) {
+        unsafe { sys::debug_info_finalize(self.inner) }
+    }
+}
+
+impl Drop for DebugInfo<'_> {
+    fn drop(&mut self) {
+        unsafe { sys::delete_debug_info(self.inner) }
+    }
+}
@@ -1,40 +1,368 @@
-#![feature(extern_types)]
-
+use std::env;
+use std::fs;
 use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
 
-mod lexer;
-use lexer::TokenReader;
+use kaleidoscope::diagnostics::Diagnostics;
+use kaleidoscope::parser::AstNode;
+use kaleidoscope::{Context, Error, OptLevel, Output, Session, Target};
 
-mod parser;
-use parser::{AstNode, Parser};
+mod link;
 
-mod codegen;
-use codegen::CodegenContext;
+const USAGE: &str = "usage: kaleidoscope [-O0|-O1|-O2|-O3|-Os] [-g] [--no-prelude]
+                    [-c | --emit=KINDS | --entry NAME]
+                    [-o OUTPUT] [--target TRIPLE] [--cpu CPU] [--features FEATURES]
+                    [--load LIBRARY]... [FILE]";
 
-fn main() {
+// the kinds of file `--emit` can write
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    Object,
+    LlvmIr,
+    LlvmBitcode,
+}
+
+impl Emit {
+    fn extension(self) -> &'static str {
+        match self {
+            Emit::Object => "o",
+            Emit::LlvmIr => "ll",
+            Emit::LlvmBitcode => "bc",
+        }
+    }
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "obj" => Ok(Emit::Object),
+            "llvm-ir" => Ok(Emit::LlvmIr),
+            "llvm-bc" => Ok(Emit::LlvmBitcode),
+            _ => Err(format!(
+                "unknown kind of output `{}`, expected obj, llvm-ir or llvm-bc",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    opt_level: OptLevel,
+    // generate debug info
+    debug: bool,
+    // leave out the functions every session otherwise starts with
+    no_prelude: bool,
+    // files to write instead of running the program
+    emit: Vec<Emit>,
+    // link an executable that calls this function instead of running the program
+    entry: Option<String>,
+    output: Option<String>,
+    target: Target,
+    // shared libraries whose functions can be declared with `extern`, also linked into executables
+    load: Vec<String>,
+    // the REPL runs when there's no file
+    path: Option<String>,
+}
+
+impl Options {
+    fn ahead_of_time(&self) -> bool {
+        !self.emit.is_empty() || self.entry.is_some()
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("`{}` needs a value", arg));
+
+        if let Some(level) = arg.strip_prefix("-O") {
+            options.opt_level = level.parse()?;
+        } else if arg == "-g" {
+            options.debug = true;
+        } else if arg == "--no-prelude" {
+            options.no_prelude = true;
+        } else if arg == "-c" {
+            options.emit.push(Emit::Object);
+        } else if let Some(kinds) = arg.strip_prefix("--emit=") {
+            for kind in kinds.split(',') {
+                options.emit.push(kind.parse()?);
+            }
+        } else if arg == "--entry" {
+            options.entry = Some(value()?);
+        } else if arg == "-o" {
+            options.output = Some(value()?);
+        } else if arg == "--load" {
+            options.load.push(value()?);
+        } else if arg == "--target" {
+            options.target.triple = Some(value()?);
+        } else if arg == "--cpu" {
+            options.target.cpu = Some(value()?);
+        } else if arg == "--features" {
+            options.target.features = Some(value()?);
+        } else if arg.starts_with('-') {
+            return Err(format!("unknown option `{}`", arg));
+        } else if options.path.is_none() {
+            options.path = Some(arg);
+        } else {
+            return Err("more than one input file".to_string());
+        }
+    }
+
+    if !options.emit.is_empty() && options.entry.is_some() {
+        return Err("`-c` and `--emit` can't be used with `--entry`".to_string());
+    }
+    // executables are linked by the system C compiler, which only knows about this machine
+    if options.entry.is_some() && !options.target.is_host() {
+        return Err(
+            "`--entry` can only link executables for this machine, use `-c` to compile for \
+             another target and link the object with a linker for it"
+                .to_string(),
+        );
+    }
+    if !options.emit.is_empty() && !options.load.is_empty() {
+        return Err("`--load` can't be used with `-c` or `--emit`".to_string());
+    }
+    // `-c --emit=obj` writes the object once
+    let mut emit = Vec::new();
+    for kind in options.emit.drain(..) {
+        if !emit.contains(&kind) {
+            emit.push(kind);
+        }
+    }
+    options.emit = emit;
+    if options.ahead_of_time() && options.path.is_none() {
+        return Err("compiling ahead of time needs an input file".to_string());
+    }
+    if !options.ahead_of_time() {
+        let target = &options.target;
+        let needs_ahead_of_time = [
+            ("-o", options.output.is_some()),
+            ("--target", target.triple.is_some()),
+            ("--cpu", target.cpu.is_some()),
+            ("--features", target.features.is_some()),
+        ];
+        if let Some((option, _)) = needs_ahead_of_time.iter().find(|(_, given)| *given) {
+            return Err(format!("`{}` needs `-c`, `--emit` or `--entry`", option));
+        }
+    }
+
+    Ok(options)
+}
+
+// `name` is what debug info calls the source
+fn start_session<'ctx>(context: &'ctx Context, options: &Options, name: &str) -> Session<'ctx> {
+    let debug_file = if options.debug { Some(name) } else { None };
+    let session = if options.ahead_of_time() {
+        Session::ahead_of_time(context, &options.target, options.opt_level, debug_file)
+            .map_err(|err| format!("couldn't generate code for the target: {}", err))
+    } else {
+        Session::new(context, options.opt_level, debug_file)
+            .map_err(|err| format!("couldn't start the JIT: {}", err))
+    };
+
+    let mut session = match session {
+        Ok(session) => session,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    };
+
+    if !options.no_prelude {
+        match session.load_prelude() {
+            Ok(()) => {}
+            Err(Error::Source {
+                source,
+                diagnostics,
+            }) => {
+                diagnostics.emit_to_stderr("<prelude>", &source);
+                process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("error: {}", err);
+                process::exit(1);
+            }
+        }
+    }
+
+    // executables link the libraries instead
+    if !options.ahead_of_time() {
+        for path in &options.load {
+            if let Err(err) = session.load_library(path) {
+                eprintln!("error: couldn't load {}: {}", path, err);
+                process::exit(1);
+            }
+        }
+    }
+
+    session
+}
+
+// compile the nodes in order, printing the IR of definitions and declarations when running in
+// the JIT and the value of top-level expressions. A node that fails to compile is reported and
+// skipped.
+fn run(
+    session: &mut Session,
+    nodes: Vec<AstNode>,
+    diagnostics: &mut Diagnostics,
+    options: &Options,
+) {
+    for node in nodes {
+        match session.run(node) {
+            Ok(Output::Compiled(_)) if options.ahead_of_time() => {}
+            Ok(Output::Compiled(ir)) => println!("{}", ir),
+            Ok(Output::Evaluated(value)) => println!("Evaluated to {}", value),
+            Err(err) => diagnostics.push(err),
+        }
+    }
+}
+
+// write the compiled program out, as the files asked for by `--emit` or an executable
+fn build(session: &mut Session, path: &str, options: &Options) -> Result<(), String> {
+    let stem = Path::new(path)
+        .file_stem()
+        .map_or("a".into(), |stem| stem.to_string_lossy());
+
+    let entry = match &options.entry {
+        Some(entry) => entry,
+        None => {
+            session.finish()?;
+            for &emit in &options.emit {
+                // with several kinds of output, `-o` only names them and each gets its extension
+                let output = match &options.output {
+                    Some(output) if options.emit.len() == 1 => output.clone(),
+                    Some(output) => Path::new(output)
+                        .with_extension(emit.extension())
+                        .to_string_lossy()
+                        .into_owned(),
+                    None => format!("{}.{}", stem, emit.extension()),
+                };
+
+                match emit {
+                    Emit::Object => session.write_object(&output)?,
+                    Emit::LlvmIr => session.write_ir(&output)?,
+                    Emit::LlvmBitcode => session.write_bitcode(&output)?,
+                }
+            }
+
+            return Ok(());
+        }
+    };
+
+    session.check_entry(entry)?;
+    session.finish()?;
+    let dir = link::TempDir::new()?;
+    let object = dir.path().join(format!("{}.o", stem));
+    let object = object.to_string_lossy();
+    session.write_object(&object)?;
+
+    let output = options.output.clone().unwrap_or_else(|| stem.to_string());
+    link::link(&object, entry, &options.load, &output)
+}
+
+fn compile_file(path: &str, options: &Options) {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("error: couldn't read {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+
+    let context = Context::new();
+    let mut session = start_session(&context, options, path);
+    let mut diagnostics = Diagnostics::default();
+    let nodes = session.parse(&source, &mut diagnostics);
+    if diagnostics.has_errors() {
+        diagnostics.emit_to_stderr(path, &source);
+        process::exit(1);
+    }
+
+    run(&mut session, nodes, &mut diagnostics, options);
+    if diagnostics.has_errors() {
+        diagnostics.emit_to_stderr(path, &source);
+        process::exit(1);
+    }
+
+    if options.ahead_of_time() {
+        if let Err(err) = build(&mut session, path, options) {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+// lines starting with `:` change settings instead of being compiled
+fn repl_command(session: &mut Session, command: &str) {
+    let mut words = command.split_whitespace();
+    match (words.next(), words.next(), words.next()) {
+        (Some("opt"), None, None) => println!("{}", session.opt_level()),
+        (Some("opt"), Some(level), None) => match level.trim_start_matches("-O").parse() {
+            Ok(level) => session.set_opt_level(level),
+            Err(err) => eprintln!("error: {}", err),
+        },
+        (Some("load-lib"), Some(path), None) => {
+            if let Err(err) = session.load_library(path) {
+                eprintln!("error: couldn't load {}: {}", path, err);
+            }
+        }
+        _ => eprintln!(
+            "error: unknown command `:{}`, expected `:opt [LEVEL]` or `:load-lib PATH`",
+            command.trim()
+        ),
+    }
+}
+
+fn repl(options: &Options) {
     let stdin = io::stdin();
     let mut stdin = stdin.lock();
     let mut stdout = io::stdout();
 
+    // functions and operators defined on one line stay usable on the next
+    let context = Context::new();
+    let mut session = start_session(&context, options, "<stdin>");
+
     let mut buf = String::new();
     loop {
         buf.clear();
         print!("ready> ");
         stdout.flush().unwrap();
 
-        stdin.read_line(&mut buf).unwrap();
-        let token_reader = TokenReader::new(buf.chars());
-        let mut parser = Parser::new(token_reader);
-
-        let expr = parser.parse_top_level();
-        let mut codegen = CodegenContext::new();
-        let func = match expr.unwrap() {
-            AstNode::Function(function) => function,
-            _ => unreachable!(),
-        };
-        let func = codegen.codegen_function(func);
-        codegen::print_function(func);
-        print!("\n");
+        if stdin.read_line(&mut buf).unwrap() == 0 {
+            break;
+        }
+
+        if let Some(command) = buf.trim_start().strip_prefix(':') {
+            repl_command(&mut session, command);
+            continue;
+        }
+
+        let mut diagnostics = Diagnostics::default();
+        let nodes = session.parse(&buf, &mut diagnostics);
+        run(&mut session, nodes, &mut diagnostics, options);
+        diagnostics.emit_to_stderr("<stdin>", &buf);
+
         stdout.flush().unwrap();
     }
 }
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+
+    match &options.path {
+        Some(path) => compile_file(path, &options),
+        None => repl(&options),
+    }
+}
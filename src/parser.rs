@@ -1,202 +1,713 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
 use std::iter::Peekable;
 
-use crate::lexer::Token;
+use crate::diagnostics::Diagnostic;
+use crate::lexer::{LexError, Span, SpannedToken, Token};
 
-fn precedence(token: Option<&Token>) -> Option<u8> {
-    token
-        .map(|token| match token {
-            Token::LessThan => Some(10),
-            Token::Plus => Some(20),
-            Token::Minus => Some(30),
-            Token::Star => Some(40),
-            _ => None,
-        })
-        .flatten()
+// the name given to the function wrapping a top-level expression
+const ANONYMOUS_FUNCTION: &str = "__anon_expr";
+
+// precedence of user-defined binary operators when the definition doesn't give one
+const DEFAULT_PRECEDENCE: u8 = 30;
+
+pub fn builtin_precedence() -> HashMap<String, u8> {
+    [
+        ("=", 2),
+        ("or", 5),
+        ("and", 6),
+        ("==", 9),
+        ("!=", 9),
+        ("<", 10),
+        ("<=", 10),
+        (">", 10),
+        (">=", 10),
+        ("+", 20),
+        ("-", 20),
+        ("*", 40),
+        ("/", 40),
+    ]
+    .iter()
+    .map(|(op, precedence)| (op.to_string(), *precedence))
+    .collect()
 }
 
-#[derive(Debug)]
+// the unary operators that aren't function calls
+const BUILTIN_UNARY: &[&str] = &["-"];
+
+/// The operators a program has available: the builtin ones and those it has defined so far.
+#[derive(Debug, Clone)]
+pub struct Operators {
+    // binary operators by spelling
+    precedence: HashMap<String, u8>,
+    // spellings of the unary operators
+    unary: HashSet<String>,
+}
+
+impl Default for Operators {
+    fn default() -> Self {
+        Operators {
+            precedence: builtin_precedence(),
+            unary: BUILTIN_UNARY.iter().map(|op| op.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum ExprAstNode {
     Number(NumberExprAstNode),
     Variable(VariableExprAstNode),
+    Unary(UnaryExprAstNode),
     Binary(BinaryExprAstNode),
     Call(CallExprAstNode),
+    If(IfExprAstNode),
+    For(ForExprAstNode),
+    Var(VarExprAstNode),
 }
 
-#[derive(Debug)]
+impl ExprAstNode {
+    pub fn span(&self) -> Span {
+        match self {
+            ExprAstNode::Number(node) => node.span,
+            ExprAstNode::Variable(node) => node.span,
+            ExprAstNode::Unary(node) => node.span,
+            ExprAstNode::Binary(node) => node.span,
+            ExprAstNode::Call(node) => node.span,
+            ExprAstNode::If(node) => node.span,
+            ExprAstNode::For(node) => node.span,
+            ExprAstNode::Var(node) => node.span,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum AstNode {
     Function(FunctionAstNode),
     Prototype(PrototypeAstNode),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NumberExprAstNode {
     pub value: f64,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VariableExprAstNode {
     pub name: String,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct UnaryExprAstNode {
+    pub op: Token,
+    pub operand: Box<ExprAstNode>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
 pub struct BinaryExprAstNode {
     pub op: Token,
     pub lhs: Box<ExprAstNode>,
     pub rhs: Box<ExprAstNode>,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CallExprAstNode {
     // should this be an ExprAstNode?
     pub callee: String,
     pub args: Vec<ExprAstNode>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct IfExprAstNode {
+    pub cond: Box<ExprAstNode>,
+    pub then: Box<ExprAstNode>,
+    pub else_: Box<ExprAstNode>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct ForExprAstNode {
+    pub var: String,
+    pub start: Box<ExprAstNode>,
+    pub end: Box<ExprAstNode>,
+    // defaults to 1.0 when not given
+    pub step: Option<Box<ExprAstNode>>,
+    pub body: Box<ExprAstNode>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub struct VarExprAstNode {
+    // variables without an initializer start out as 0.0
+    pub vars: Vec<(String, Option<ExprAstNode>)>,
+    pub body: Box<ExprAstNode>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PrototypeKind {
+    Function,
+    Unary,
+    Binary(u8),
 }
 
-#[derive(Debug, Default)]
+impl Default for PrototypeKind {
+    fn default() -> Self {
+        PrototypeKind::Function
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct PrototypeAstNode {
+    /// For operators this is `unary` or `binary` followed by the operator, e.g. `binary|`.
     pub name: String,
     pub args: Vec<String>,
+    pub kind: PrototypeKind,
+    pub span: Span,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FunctionAstNode {
     pub prototype: PrototypeAstNode,
     pub body: Box<ExprAstNode>,
+    pub span: Span,
 }
 
-#[derive(Debug)]
-pub struct Parser<T: Iterator<Item = Token>> {
-    tokens: Peekable<T>,
+impl FunctionAstNode {
+    /// Whether this is a top-level expression rather than a `def`.
+    pub fn is_anonymous(&self) -> bool {
+        self.prototype.name == ANONYMOUS_FUNCTION
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// Found a token the grammar doesn't allow here.
+    UnexpectedToken {
+        expected: &'static str,
+        found: Token,
+        span: Span,
+    },
+    /// The input ended in the middle of an item.
+    UnexpectedEof { expected: &'static str, span: Span },
+    /// A `(` was never matched by a `)`.
+    Unclosed {
+        open: Span,
+        found: Token,
+        span: Span,
+    },
+    /// An operator definition with the wrong number of operands.
+    OperatorArity {
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    /// Something other than a variable on the left of `=`.
+    InvalidAssignment { span: Span },
+    /// A binary operator precedence outside of 1 to 100.
+    InvalidPrecedence { span: Span },
+    /// A definition of an operator the language already has.
+    BuiltinOperator { op: String, span: Span },
+    /// An operator used before any definition of it.
+    UnknownOperator { op: String, span: Span },
+    /// The lexer couldn't produce a token here.
+    Lex { error: LexError, span: Span },
 }
 
-impl<T: Iterator<Item = Token>> Parser<T> {
-    fn parse_number_expr(&mut self) -> Result<ExprAstNode, ()> {
-        let token = self.tokens.next();
-        match token {
-            Some(Token::Number(value)) => Ok(ExprAstNode::Number(NumberExprAstNode { value })),
-            _ => Err(()),
+impl ParseError {
+    pub fn span(&self) -> Span {
+        match self {
+            ParseError::UnexpectedToken { span, .. } => *span,
+            ParseError::UnexpectedEof { span, .. } => *span,
+            ParseError::Unclosed { span, .. } => *span,
+            ParseError::OperatorArity { span, .. } => *span,
+            ParseError::InvalidAssignment { span } => *span,
+            ParseError::InvalidPrecedence { span } => *span,
+            ParseError::BuiltinOperator { span, .. } => *span,
+            ParseError::UnknownOperator { span, .. } => *span,
+            ParseError::Lex { span, .. } => *span,
         }
     }
 
-    fn parse_paren_expr(&mut self) -> Result<ExprAstNode, ()> {
-        let token = self.tokens.next();
-        if token.is_some() && token.unwrap() != Token::LParen {
-            return Err(());
+    // `expected` errors about a missing `)` are more helpful when they point at its `(` too
+    fn unclosed(self, open: Span) -> Self {
+        match self {
+            ParseError::UnexpectedToken {
+                expected: "`)`",
+                found,
+                span,
+            } => ParseError::Unclosed { open, found, span },
+            ParseError::UnexpectedEof {
+                expected: "`)`",
+                span,
+            } => ParseError::Unclosed {
+                open,
+                found: Token::Eof,
+                span,
+            },
+            err => err,
         }
+    }
+}
 
-        if self.tokens.peek() == Some(&Token::RParen) {
-            return Err(());
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken {
+                expected, found, ..
+            } => write!(f, "expected {}, found {}", expected, found),
+            ParseError::UnexpectedEof { expected, .. } => {
+                write!(f, "expected {}, found end of input", expected)
+            }
+            ParseError::Unclosed { found, .. } => write!(f, "expected `)`, found {}", found),
+            ParseError::OperatorArity {
+                expected, found, ..
+            } => write!(
+                f,
+                "operator takes {} operand{}, but {} {} given",
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                found,
+                if *found == 1 { "was" } else { "were" }
+            ),
+            ParseError::InvalidAssignment { .. } => write!(f, "can only assign to a variable"),
+            ParseError::InvalidPrecedence { .. } => {
+                write!(f, "operator precedence must be between 1 and 100")
+            }
+            ParseError::BuiltinOperator { op, .. } => {
+                write!(f, "can't redefine the builtin operator `{}`", op)
+            }
+            ParseError::UnknownOperator { op, .. } => write!(f, "unknown operator `{}`", op),
+            ParseError::Lex { error, .. } => write!(f, "{}", error),
         }
-        let expr = self.parse_expression()?;
+    }
+}
+
+impl Error for ParseError {}
 
-        let token = self.tokens.next();
-        if token.is_some() && token.unwrap() != Token::RParen {
-            return Err(());
+impl From<ParseError> for Diagnostic {
+    fn from(err: ParseError) -> Self {
+        let (message, span) = (err.to_string(), err.span());
+        match err {
+            ParseError::UnexpectedToken { expected, .. } => {
+                Diagnostic::error("E0100", message, span)
+                    .with_label(format!("expected {}", expected))
+            }
+            ParseError::UnexpectedEof { expected, .. } => Diagnostic::error("E0101", message, span)
+                .with_label(format!("expected {}", expected)),
+            ParseError::Unclosed { open, .. } => Diagnostic::error("E0102", message, span)
+                .with_label("expected `)`".to_string())
+                .with_secondary(open, "unclosed delimiter".to_string()),
+            ParseError::OperatorArity { expected, .. } => Diagnostic::error("E0103", message, span)
+                .with_label(format!("expected {} operand(s)", expected)),
+            ParseError::InvalidAssignment { .. } => {
+                Diagnostic::error("E0105", message, span).with_label("not a variable".to_string())
+            }
+            ParseError::InvalidPrecedence { .. } => Diagnostic::error("E0104", message, span)
+                .with_label("precedence out of range".to_string()),
+            ParseError::BuiltinOperator { .. } => {
+                Diagnostic::error("E0106", message, span).with_label("builtin operator".to_string())
+            }
+            ParseError::UnknownOperator { .. } => Diagnostic::error("E0107", message, span)
+                .with_label("not defined".to_string())
+                .with_note("operators are defined with `def unary` or `def binary`".to_string()),
+            ParseError::Lex { error, .. } => error.to_diagnostic(span),
         }
+    }
+}
 
-        Ok(expr)
+#[derive(Debug)]
+pub struct Parser<T: Iterator<Item = SpannedToken>> {
+    tokens: Peekable<T>,
+    // span of the most recently consumed token, used to close off the span of a node
+    last_span: Span,
+    // including the ones defined by the program
+    operators: Operators,
+}
+
+impl<T: Iterator<Item = SpannedToken>> Parser<T> {
+    fn peek(&mut self) -> Option<&Token> {
+        self.tokens.peek().map(|token| &token.token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let SpannedToken { token, span } = self.tokens.next()?;
+        self.last_span = span;
+
+        Some(token)
+    }
+
+    fn peek_precedence(&mut self) -> Option<u8> {
+        let op = self.peek()?.operator()?;
+        self.operators.precedence.get(&op).copied()
     }
 
-    fn parse_identifier_expr(&mut self) -> Result<ExprAstNode, ()> {
-        let token = self.tokens.peek();
-        if token.is_some() && !matches!(token, Some(Token::Identifier(_))) {
-            return Err(());
+    // build the error for the next token not being what the grammar wants
+    fn unexpected(&mut self, expected: &'static str) -> ParseError {
+        match self.tokens.peek() {
+            None
+            | Some(SpannedToken {
+                token: Token::Eof, ..
+            }) => ParseError::UnexpectedEof {
+                expected,
+                span: self
+                    .tokens
+                    .peek()
+                    .map_or(self.last_span, |token| token.span),
+            },
+            Some(SpannedToken {
+                token: Token::Error(error),
+                span,
+            }) => ParseError::Lex {
+                error: error.clone(),
+                span: *span,
+            },
+            Some(SpannedToken { token, span }) => ParseError::UnexpectedToken {
+                expected,
+                found: token.clone(),
+                span: *span,
+            },
         }
+    }
+
+    // consume the next token if it is `token`, otherwise fail without consuming anything
+    fn expect(&mut self, token: Token, expected: &'static str) -> Result<(), ParseError> {
+        if self.peek() != Some(&token) {
+            return Err(self.unexpected(expected));
+        }
+        self.next();
+
+        Ok(())
+    }
 
-        let token = self.tokens.next().unwrap();
-        let name = match token {
-            Token::Identifier(name) => name,
-            _ => unreachable!(),
+    fn parse_number_expr(&mut self) -> Result<ExprAstNode, ParseError> {
+        let value = match self.peek() {
+            Some(Token::Number(value)) => *value,
+            _ => return Err(self.unexpected("number")),
         };
+        self.next();
+
+        Ok(ExprAstNode::Number(NumberExprAstNode {
+            value,
+            span: self.last_span,
+        }))
+    }
+
+    fn parse_paren_expr(&mut self) -> Result<ExprAstNode, ParseError> {
+        self.expect(Token::LParen, "`(`")?;
+        let open = self.last_span;
+
+        let expr = self.parse_expression()?;
+
+        self.expect(Token::RParen, "`)`")
+            .map_err(|err| err.unclosed(open))?;
+
+        Ok(expr)
+    }
+
+    fn parse_identifier_expr(&mut self) -> Result<ExprAstNode, ParseError> {
+        let name = match self.peek() {
+            Some(Token::Identifier(_)) => match self.next() {
+                Some(Token::Identifier(name)) => name,
+                _ => unreachable!(),
+            },
+            _ => return Err(self.unexpected("identifier")),
+        };
+        let start = self.last_span;
 
         // not a call
-        let token = self.tokens.peek();
-        if token.is_some() && !matches!(token.unwrap(), Token::LParen) {
-            return Ok(ExprAstNode::Variable(VariableExprAstNode { name }));
+        if !matches!(self.peek(), Some(Token::LParen)) {
+            return Ok(ExprAstNode::Variable(VariableExprAstNode {
+                name,
+                span: start,
+            }));
         }
 
         // a call
-        self.tokens.next();
+        self.next();
+        let open = self.last_span;
         let mut args = Vec::new();
-        if !matches!(self.tokens.peek(), Some(Token::RParen)) {
+        if !matches!(self.peek(), Some(Token::RParen)) {
             loop {
                 let expr = self.parse_expression()?;
                 args.push(expr);
 
-                if matches!(self.tokens.peek(), Some(Token::RParen)) {
+                if matches!(self.peek(), Some(Token::RParen)) {
                     break;
                 }
-                if !matches!(self.tokens.peek(), Some(Token::Comma)) {
-                    return Err(());
+                if !matches!(self.peek(), Some(Token::Comma)) {
+                    return Err(self.unexpected("`)`").unclosed(open));
                 }
 
                 // eat ','.
-                self.tokens.next();
+                self.next();
             }
         }
 
         // eat ')'.
-        self.tokens.next();
+        self.next();
 
         let callee = name;
-        Ok(ExprAstNode::Call(CallExprAstNode { callee, args }))
+        Ok(ExprAstNode::Call(CallExprAstNode {
+            callee,
+            args,
+            span: start.to(self.last_span),
+        }))
     }
 
-    fn parse_primary(&mut self) -> Result<ExprAstNode, ()> {
-        match self.tokens.peek() {
+    fn parse_if_expr(&mut self) -> Result<ExprAstNode, ParseError> {
+        self.expect(Token::If, "`if`")?;
+        let start = self.last_span;
+
+        let cond = Box::new(self.parse_expression()?);
+        self.expect(Token::Then, "`then`")?;
+        let then = Box::new(self.parse_expression()?);
+        self.expect(Token::Else, "`else`")?;
+        let else_ = Box::new(self.parse_expression()?);
+
+        let span = start.to(else_.span());
+        Ok(ExprAstNode::If(IfExprAstNode {
+            cond,
+            then,
+            else_,
+            span,
+        }))
+    }
+
+    fn parse_for_expr(&mut self) -> Result<ExprAstNode, ParseError> {
+        self.expect(Token::For, "`for`")?;
+        let start_span = self.last_span;
+
+        let var = match self.peek() {
+            Some(Token::Identifier(_)) => match self.next() {
+                Some(Token::Identifier(name)) => name,
+                _ => unreachable!(),
+            },
+            _ => return Err(self.unexpected("loop variable")),
+        };
+
+        self.expect(Token::Equals, "`=`")?;
+        let start = Box::new(self.parse_expression()?);
+        self.expect(Token::Comma, "`,`")?;
+        let end = Box::new(self.parse_expression()?);
+
+        let step = if self.peek() == Some(&Token::Comma) {
+            self.next();
+            Some(Box::new(self.parse_expression()?))
+        } else {
+            None
+        };
+
+        self.expect(Token::In, "`in`")?;
+        let body = Box::new(self.parse_expression()?);
+
+        let span = start_span.to(body.span());
+        Ok(ExprAstNode::For(ForExprAstNode {
+            var,
+            start,
+            end,
+            step,
+            body,
+            span,
+        }))
+    }
+
+    fn parse_primary(&mut self) -> Result<ExprAstNode, ParseError> {
+        match self.peek() {
             Some(Token::Identifier(_)) => self.parse_identifier_expr(),
             Some(Token::Number(_)) => self.parse_number_expr(),
             Some(Token::LParen) => self.parse_paren_expr(),
-            _ => Err(()),
+            Some(Token::If) => self.parse_if_expr(),
+            Some(Token::For) => self.parse_for_expr(),
+            Some(Token::Var) => self.parse_var_expr(),
+            _ => Err(self.unexpected("expression")),
+        }
+    }
+
+    fn parse_var_expr(&mut self) -> Result<ExprAstNode, ParseError> {
+        self.expect(Token::Var, "`var`")?;
+        let start = self.last_span;
+
+        let mut vars = Vec::new();
+        loop {
+            let name = match self.peek() {
+                Some(Token::Identifier(_)) => match self.next() {
+                    Some(Token::Identifier(name)) => name,
+                    _ => unreachable!(),
+                },
+                _ => return Err(self.unexpected("variable name")),
+            };
+
+            let init = if self.peek() == Some(&Token::Equals) {
+                self.next();
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+            vars.push((name, init));
+
+            if self.peek() != Some(&Token::Comma) {
+                break;
+            }
+            self.next();
+        }
+
+        self.expect(Token::In, "`in`")?;
+        let body = Box::new(self.parse_expression()?);
+
+        let span = start.to(body.span());
+        Ok(ExprAstNode::Var(VarExprAstNode { vars, body, span }))
+    }
+
+    // a unary operator applied to an expression, or just a primary expression
+    fn parse_unary(&mut self) -> Result<ExprAstNode, ParseError> {
+        match self.peek().and_then(Token::operator) {
+            Some(op) if self.operators.unary.contains(&op) => (),
+            // a binary operator here is just a missing operand, left to `parse_primary` to report
+            Some(op)
+                if matches!(self.peek(), Some(Token::Operator(_)))
+                    && !self.operators.precedence.contains_key(&op) =>
+            {
+                let span = self.tokens.peek().unwrap().span;
+                return Err(ParseError::UnknownOperator { op, span });
+            }
+            _ => return self.parse_primary(),
         }
+
+        let op = self.next().unwrap();
+        let start = self.last_span;
+        let operand = Box::new(self.parse_unary()?);
+
+        let span = start.to(operand.span());
+        Ok(ExprAstNode::Unary(UnaryExprAstNode { op, operand, span }))
     }
 
     fn parse_bin_op_rhs(
         &mut self,
         min_precedence: u8,
         mut lhs: ExprAstNode,
-    ) -> Result<ExprAstNode, ()> {
+    ) -> Result<ExprAstNode, ParseError> {
         loop {
-            let token_precedence = precedence(self.tokens.peek()).unwrap_or(0);
-            if precedence(self.tokens.peek()).is_none() || token_precedence < min_precedence {
-                return Ok(lhs);
-            }
+            let token_precedence = match self.peek_precedence() {
+                Some(precedence) if precedence >= min_precedence => precedence,
+                Some(_) => return Ok(lhs),
+                // an operator after an operand that isn't a binary one would otherwise end the
+                // expression early, and leave the rest of it to be reported out of place
+                None => match self.tokens.peek() {
+                    Some(SpannedToken {
+                        token: Token::Operator(op),
+                        span,
+                    }) => {
+                        return Err(ParseError::UnknownOperator {
+                            op: op.to_string(),
+                            span: *span,
+                        })
+                    }
+                    _ => return Ok(lhs),
+                },
+            };
 
             // we have a binary op
-            let op = self.tokens.next().unwrap();
-            let mut rhs = self.parse_primary()?;
+            let op = self.next().unwrap();
+            let mut rhs = self.parse_unary()?;
 
-            let next_precedence = precedence(self.tokens.peek()).unwrap_or(0);
-            if precedence(Some(&op)).unwrap() < next_precedence {
-                rhs = self.parse_bin_op_rhs(token_precedence, rhs)?;
+            // if the next operator binds tighter it takes `rhs` as its lhs; operators of equal
+            // precedence are left for this loop, which makes them left associative
+            let next_precedence = self.peek_precedence().unwrap_or(0);
+            if token_precedence < next_precedence {
+                rhs = self.parse_bin_op_rhs(token_precedence + 1, rhs)?;
             }
 
+            if op == Token::Equals && !matches!(lhs, ExprAstNode::Variable(_)) {
+                return Err(ParseError::InvalidAssignment { span: lhs.span() });
+            }
+
+            let span = lhs.span().to(rhs.span());
             lhs = ExprAstNode::Binary(BinaryExprAstNode {
                 op,
                 lhs: Box::new(lhs),
                 rhs: Box::new(rhs),
+                span,
             });
         }
     }
 
-    fn parse_prototype(&mut self) -> Result<PrototypeAstNode, ()> {
-        if !matches!(self.tokens.peek(), Some(Token::Identifier(_))) {
-            return Err(());
+    // the operator after `unary` or `binary` in a prototype, which mustn't be one of the builtin
+    // operators in `builtin`
+    fn parse_operator_name<'a>(
+        &mut self,
+        builtin: impl IntoIterator<Item = &'a str>,
+    ) -> Result<String, ParseError> {
+        let op = match self.peek().and_then(Token::operator) {
+            Some(op) => op,
+            None => return Err(self.unexpected("operator")),
+        };
+        self.next();
+
+        if builtin.into_iter().any(|builtin| builtin == op) {
+            return Err(ParseError::BuiltinOperator {
+                op,
+                span: self.last_span,
+            });
         }
 
-        let name = match self.tokens.next().unwrap() {
-            Token::Identifier(name) => name,
-            _ => unreachable!(),
+        Ok(op)
+    }
+
+    fn parse_prototype(&mut self) -> Result<PrototypeAstNode, ParseError> {
+        let (name, kind, start) = match self.peek() {
+            Some(Token::Identifier(_)) => match self.next() {
+                Some(Token::Identifier(name)) => (name, PrototypeKind::Function, self.last_span),
+                _ => unreachable!(),
+            },
+            Some(Token::Unary) => {
+                self.next();
+                let start = self.last_span;
+                let op = self.parse_operator_name(BUILTIN_UNARY.iter().copied())?;
+
+                (format!("unary{}", op), PrototypeKind::Unary, start)
+            }
+            Some(Token::Binary) => {
+                self.next();
+                let start = self.last_span;
+                let builtin = builtin_precedence();
+                let op = self.parse_operator_name(builtin.keys().map(String::as_str))?;
+
+                let precedence = match self.peek() {
+                    Some(Token::Number(precedence)) => {
+                        let precedence = *precedence;
+                        self.next();
+                        if !(1.0..=100.0).contains(&precedence) {
+                            return Err(ParseError::InvalidPrecedence {
+                                span: self.last_span,
+                            });
+                        }
+
+                        precedence as u8
+                    }
+                    _ => DEFAULT_PRECEDENCE,
+                };
+
+                (
+                    format!("binary{}", op),
+                    PrototypeKind::Binary(precedence),
+                    start,
+                )
+            }
+            _ => return Err(self.unexpected("function name")),
         };
 
-        if !matches!(self.tokens.peek(), Some(Token::LParen)) {
-            return Err(());
-        }
-        self.tokens.next();
+        self.expect(Token::LParen, "`(`")?;
+        let open = self.last_span;
 
         let mut args = Vec::new();
-        while let Some(Token::Identifier(_)) = self.tokens.peek() {
-            let name = match self.tokens.next().unwrap() {
+        while let Some(Token::Identifier(_)) = self.peek() {
+            let name = match self.next().unwrap() {
                 Token::Identifier(name) => name,
                 _ => unreachable!(),
             };
@@ -204,63 +715,327 @@ impl<T: Iterator<Item = Token>> Parser<T> {
             args.push(name);
         }
 
-        if !matches!(self.tokens.peek(), Some(Token::RParen)) {
-            return Err(());
+        self.expect(Token::RParen, "`)`")
+            .map_err(|err| err.unclosed(open))?;
+        let span = start.to(self.last_span);
+
+        let operands = match kind {
+            PrototypeKind::Function => None,
+            PrototypeKind::Unary => Some(1),
+            PrototypeKind::Binary(_) => Some(2),
+        };
+        if let Some(expected) = operands {
+            if args.len() != expected {
+                return Err(ParseError::OperatorArity {
+                    expected,
+                    found: args.len(),
+                    span,
+                });
+            }
+        }
+
+        // later expressions can use the operator as soon as it has been declared
+        match kind {
+            PrototypeKind::Function => (),
+            PrototypeKind::Unary => {
+                self.operators
+                    .unary
+                    .insert(name["unary".len()..].to_string());
+            }
+            PrototypeKind::Binary(precedence) => {
+                self.operators
+                    .precedence
+                    .insert(name["binary".len()..].to_string(), precedence);
+            }
         }
-        self.tokens.next();
 
-        Ok(PrototypeAstNode { name, args })
+        Ok(PrototypeAstNode {
+            name,
+            args,
+            kind,
+            span,
+        })
     }
 
-    fn parse_definition(&mut self) -> Result<FunctionAstNode, ()> {
-        if !matches!(self.tokens.peek(), Some(Token::Def)) {
-            return Err(());
-        }
-        self.tokens.next();
+    fn parse_definition(&mut self) -> Result<FunctionAstNode, ParseError> {
+        self.expect(Token::Def, "`def`")?;
+        let start = self.last_span;
 
         let prototype = self.parse_prototype()?;
         let body = Box::new(self.parse_expression()?);
+        let span = start.to(body.span());
 
-        Ok(FunctionAstNode { prototype, body })
+        Ok(FunctionAstNode {
+            prototype,
+            body,
+            span,
+        })
     }
 
-    fn parse_extern(&mut self) -> Result<PrototypeAstNode, ()> {
-        if !matches!(self.tokens.peek(), Some(Token::Extern)) {
-            return Err(());
-        }
-        self.tokens.next();
+    fn parse_extern(&mut self) -> Result<PrototypeAstNode, ParseError> {
+        self.expect(Token::Extern, "`extern`")?;
+        let start = self.last_span;
 
-        self.parse_prototype()
+        let prototype = self.parse_prototype()?;
+        Ok(PrototypeAstNode {
+            span: start.to(prototype.span),
+            ..prototype
+        })
     }
 
-    fn parse_top_level_expr(&mut self) -> Result<AstNode, ()> {
+    fn parse_top_level_expr(&mut self) -> Result<AstNode, ParseError> {
         let body = Box::new(self.parse_expression()?);
+        let span = body.span();
 
         Ok(AstNode::Function(FunctionAstNode {
-            prototype: Default::default(),
+            prototype: PrototypeAstNode {
+                name: ANONYMOUS_FUNCTION.to_string(),
+                span,
+                ..Default::default()
+            },
             body,
+            span,
         }))
     }
 
-    pub fn parse_expression(&mut self) -> Result<ExprAstNode, ()> {
-        let lhs = self.parse_primary()?;
+    pub fn parse_expression(&mut self) -> Result<ExprAstNode, ParseError> {
+        let lhs = self.parse_unary()?;
 
         self.parse_bin_op_rhs(0, lhs)
     }
 
-    pub fn parse_top_level(&mut self) -> Option<AstNode> {
-        match self.tokens.peek() {
-            Some(Token::Eof) => None,
-            Some(Token::Semicolon) => None,
-            Some(Token::Def) => Some(AstNode::Function(self.parse_definition().unwrap())),
-            Some(Token::Extern) => Some(AstNode::Prototype(self.parse_extern().unwrap())),
-            _ => Some(self.parse_top_level_expr().unwrap()),
+    pub fn parse_top_level(&mut self) -> Result<Option<AstNode>, ParseError> {
+        match self.peek() {
+            Some(Token::Eof) => Ok(None),
+            Some(Token::Semicolon) => Ok(None),
+            Some(Token::Def) => Ok(Some(AstNode::Function(self.parse_definition()?))),
+            Some(Token::Extern) => Ok(Some(AstNode::Prototype(self.parse_extern()?))),
+            _ => Ok(Some(self.parse_top_level_expr()?)),
+        }
+    }
+
+    // after an error, skip to something that can start a new top-level item
+    fn synchronize(&mut self) {
+        loop {
+            match self.peek() {
+                None | Some(Token::Eof) | Some(Token::Def) | Some(Token::Extern) => return,
+                Some(Token::Semicolon) => {
+                    self.next();
+                    return;
+                }
+                _ => {
+                    self.next();
+                }
+            }
+        }
+    }
+
+    /// Parse every top-level item up to the end of input. An error doesn't stop parsing: the bad
+    /// item is skipped and parsing resumes at the next `def`, `extern` or `;`.
+    pub fn parse_program(&mut self) -> (Vec<AstNode>, Vec<ParseError>) {
+        let mut nodes = Vec::new();
+        let mut errors = Vec::new();
+
+        loop {
+            match self.peek() {
+                None | Some(Token::Eof) => break,
+                Some(Token::Semicolon) => {
+                    self.next();
+                    continue;
+                }
+                _ => (),
+            }
+
+            match self.parse_top_level() {
+                Ok(Some(node)) => nodes.push(node),
+                Ok(None) => (),
+                Err(err) => {
+                    errors.push(err);
+                    self.synchronize();
+                }
+            }
         }
+
+        (nodes, errors)
+    }
+
+    /// Carry on with the operators defined by earlier input, e.g. a previous line of the REPL.
+    pub fn with_operators(mut self, operators: Operators) -> Self {
+        self.operators = operators;
+        self
+    }
+
+    pub fn into_operators(self) -> Operators {
+        self.operators
     }
 
     pub fn new(tokens: T) -> Self {
         Parser {
             tokens: tokens.peekable(),
+            last_span: Span::default(),
+            operators: Operators::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::TokenReader;
+
+    fn parse(source: &str) -> (Vec<AstNode>, Vec<ParseError>) {
+        Parser::new(TokenReader::new(source.chars())).parse_program()
+    }
+
+    fn names(nodes: &[AstNode]) -> Vec<&str> {
+        nodes
+            .iter()
+            .map(|node| match node {
+                AstNode::Function(function) => function.prototype.name.as_str(),
+                AstNode::Prototype(prototype) => prototype.name.as_str(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reports_every_broken_definition() {
+        let source = "def f(x) x +;\n\
+                      def g(x) x * 2;\n\
+                      def (x) 1;\n\
+                      extern h(a b);\n\
+                      def k(x x;\n\
+                      4 + 5;";
+        let (nodes, errors) = parse(source);
+
+        assert_eq!(names(&nodes), vec!["g", "h", ANONYMOUS_FUNCTION]);
+
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(
+            &errors[0],
+            ParseError::UnexpectedToken {
+                expected: "expression",
+                found: Token::Semicolon,
+                ..
+            }
+        ));
+        assert_eq!(errors[0].span().line, 1);
+        assert!(matches!(
+            &errors[1],
+            ParseError::UnexpectedToken {
+                expected: "function name",
+                found: Token::LParen,
+                ..
+            }
+        ));
+        assert_eq!(errors[1].span().line, 3);
+        assert!(matches!(&errors[2], ParseError::Unclosed { .. }));
+        assert_eq!(errors[2].span().line, 5);
+    }
+
+    #[test]
+    fn lex_errors_are_recovered_from() {
+        let (nodes, errors) = parse("def f(x) 1.2.3;\ndef g() 2;");
+
+        assert_eq!(names(&nodes), vec!["g"]);
+        assert_eq!(
+            errors,
+            vec![ParseError::Lex {
+                error: LexError::InvalidNumber("1.2.3".to_string()),
+                span: Span {
+                    start: 9,
+                    end: 14,
+                    line: 1,
+                    column: 10
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn builtin_operators_cannot_be_redefined() {
+        let source = "def binary+ 90 (a b) a;\n\
+                      def binary== (a b) a;\n\
+                      def binary and (a b) a;\n\
+                      def unary-(v) v;\n\
+                      def unary+(v) v;";
+        let (nodes, errors) = parse(source);
+
+        assert_eq!(names(&nodes), vec!["unary+"]);
+        let ops: Vec<_> = errors
+            .iter()
+            .map(|err| match err {
+                ParseError::BuiltinOperator { op, .. } => op.as_str(),
+                err => panic!("unexpected error {:?}", err),
+            })
+            .collect();
+        assert_eq!(ops, vec!["+", "==", "and", "-"]);
+        assert_eq!(errors[0].span().column, 11);
+    }
+
+    #[test]
+    fn builtin_precedence_is_kept() {
+        let (nodes, errors) = parse("def binary+ 90 (a b) a; 2 * 3 + 4;");
+
+        assert_eq!(errors.len(), 1);
+        let body = match &nodes[0] {
+            AstNode::Function(function) => &function.body,
+            node => panic!("unexpected node {:?}", node),
+        };
+        assert!(matches!(
+            &**body,
+            ExprAstNode::Binary(BinaryExprAstNode {
+                op: Token::Plus,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn undeclared_operators_are_unknown() {
+        let (nodes, errors) = parse("@;\ndef unary@(v) v;\n@1;\n3 + !2;\ndef f(x) x | 1;\n4 @ 5;");
+
+        assert_eq!(names(&nodes), vec!["unary@", ANONYMOUS_FUNCTION]);
+        assert_eq!(
+            errors,
+            vec![
+                ParseError::UnknownOperator {
+                    op: "@".to_string(),
+                    span: Span {
+                        start: 0,
+                        end: 1,
+                        line: 1,
+                        column: 1
+                    },
+                },
+                ParseError::UnknownOperator {
+                    op: "!".to_string(),
+                    span: Span {
+                        start: 28,
+                        end: 29,
+                        line: 4,
+                        column: 5
+                    },
+                },
+                // only declared as unary, so it's unknown between operands
+                ParseError::UnknownOperator {
+                    op: "|".to_string(),
+                    span: Span {
+                        start: 43,
+                        end: 44,
+                        line: 5,
+                        column: 12
+                    },
+                },
+                ParseError::UnknownOperator {
+                    op: "@".to_string(),
+                    span: Span {
+                        start: 50,
+                        end: 51,
+                        line: 6,
+                        column: 3
+                    },
+                },
+            ]
+        );
+    }
+}
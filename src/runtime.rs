@@ -0,0 +1,138 @@
+//! The builtin functions Kaleidoscope programs can declare with `extern` and call. The JIT is
+//! given their addresses, and the same file is built into the static archive that executables
+//! compiled ahead of time are linked against.
+//!
+//! The archive is built with `runtime_archive` set, and without `std`, so that it only needs the
+//! C library. Its symbols are the names of the builtins with `kaleidoscope_` in front, see
+//! `archive_symbol`, so that `clock` and `random` don't take the place of the C library's in the
+//! libraries an executable is linked with.
+
+#![cfg_attr(runtime_archive, no_std)]
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+extern "C" {
+    fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+    fn clock_gettime(clock: i32, time: *mut Timespec) -> i32;
+}
+
+#[repr(C)]
+struct Timespec {
+    seconds: i64,
+    nanoseconds: i64,
+}
+
+#[cfg(target_os = "macos")]
+const CLOCK_MONOTONIC: i32 = 6;
+#[cfg(not(target_os = "macos"))]
+const CLOCK_MONOTONIC: i32 = 1;
+
+// writes straight to the file descriptor so that output isn't held back in a buffer, and comes
+// out in order with whatever the REPL prints
+struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        while !bytes.is_empty() {
+            let written = unsafe { write(1, bytes.as_ptr(), bytes.len()) };
+            if written <= 0 {
+                return Err(fmt::Error);
+            }
+            bytes = &bytes[written as usize..];
+        }
+
+        Ok(())
+    }
+}
+
+/// Print the byte `c`, and return 0.
+#[cfg_attr(runtime_archive, export_name = "kaleidoscope_putchard")]
+pub extern "C" fn putchard(c: f64) -> f64 {
+    // as a raw byte, so that codes from 128 up aren't encoded as UTF-8
+    let byte = [c as u8];
+    unsafe { write(1, byte.as_ptr(), 1) };
+    0.0
+}
+
+/// Print `x` and a newline, and return 0.
+#[cfg_attr(runtime_archive, export_name = "kaleidoscope_printd")]
+pub extern "C" fn printd(x: f64) -> f64 {
+    let _ = writeln!(Stdout, "{:.6}", x);
+    0.0
+}
+
+/// Print a newline, and return 0.
+#[cfg_attr(runtime_archive, export_name = "kaleidoscope_printnl")]
+pub extern "C" fn printnl() -> f64 {
+    let _ = Stdout.write_char('\n');
+    0.0
+}
+
+/// Seconds since some fixed point in the past, for timing things.
+#[cfg_attr(runtime_archive, export_name = "kaleidoscope_clock")]
+pub extern "C" fn clock() -> f64 {
+    let mut time = Timespec {
+        seconds: 0,
+        nanoseconds: 0,
+    };
+    unsafe { clock_gettime(CLOCK_MONOTONIC, &mut time) };
+
+    time.seconds as f64 + time.nanoseconds as f64 / 1e9
+}
+
+// every run gives the same numbers until `seed` is called
+static RANDOM_STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Restart the numbers returned by `random` from `seed`, and return 0.
+#[cfg_attr(runtime_archive, export_name = "kaleidoscope_seed")]
+pub extern "C" fn seed(seed: f64) -> f64 {
+    RANDOM_STATE.store(seed.to_bits(), Ordering::Relaxed);
+    0.0
+}
+
+/// A pseudo-random number in `[0, 1)`.
+#[cfg_attr(runtime_archive, export_name = "kaleidoscope_random")]
+pub extern "C" fn random() -> f64 {
+    // splitmix64
+    let state = RANDOM_STATE
+        .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+
+    // the top 53 bits fill the mantissa of a double exactly
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The name and address of every builtin, for the JIT.
+#[cfg(not(runtime_archive))]
+pub fn builtins() -> Vec<(&'static str, usize)> {
+    vec![
+        ("putchard", putchard as usize),
+        ("printd", printd as usize),
+        ("printnl", printnl as usize),
+        ("clock", clock as usize),
+        ("seed", seed as usize),
+        ("random", random as usize),
+    ]
+}
+
+/// The symbol the archive defines for the builtin `name`.
+#[cfg(not(runtime_archive))]
+pub fn archive_symbol(name: &str) -> String {
+    format!("kaleidoscope_{}", name)
+}
+
+#[cfg(runtime_archive)]
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    extern "C" {
+        fn abort() -> !;
+    }
+
+    unsafe { abort() }
+}
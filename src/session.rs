@@ -0,0 +1,377 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::mem;
+
+use crate::codegen::{CodegenContext, CodegenError, OptLevel, Target};
+use crate::diagnostics::{Diagnostic, Diagnostics};
+use crate::host::HostFn;
+use crate::lexer::TokenReader;
+use crate::llvm::{Context, Function, TargetMachine};
+use crate::parser::{AstNode, FunctionAstNode, Operators, Parser};
+
+// compiled before anything else unless turned off with `--no-prelude`
+const PRELUDE: &str = include_str!("prelude.ks");
+
+/// Why `load_prelude`, `eval`, `define` or `call` failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The source has errors, described by diagnostics pointing into it.
+    Source {
+        source: String,
+        diagnostics: Diagnostics,
+    },
+    /// `eval` of source without an expression in it.
+    NoExpression,
+    /// A function that can't be called with the arguments given, or couldn't be run.
+    Call(CodegenError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Source {
+                source,
+                diagnostics,
+            } => {
+                let mut out = Vec::new();
+                diagnostics
+                    .emit(&mut out, "<source>", source, false)
+                    .map_err(|_| fmt::Error)?;
+                write!(f, "{}", String::from_utf8_lossy(&out).trim_end())
+            }
+            Error::NoExpression => write!(f, "there's no expression to evaluate"),
+            Error::Call(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// What `run` made of one item of the input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    /// The IR of a definition or declaration, after optimization.
+    Compiled(String),
+    /// The value of a top-level expression.
+    Evaluated(f64),
+}
+
+/// Everything that outlives a single piece of input: user-defined operators, declared and
+/// compiled functions, and the JIT they live in. Each REPL line adds to the same program.
+pub struct Session<'ctx> {
+    codegen: CodegenContext<'ctx>,
+    operators: Operators,
+    // set when compiling ahead of time rather than running in the JIT
+    target_machine: Option<TargetMachine>,
+    // definitions of the prelude that are only declared until `finish`, when compiling ahead of
+    // time
+    prelude: Vec<FunctionAstNode>,
+    // the closures registered with `register_fn`, which the JIT has pointers to
+    host_functions: HashMap<String, Box<dyn Any>>,
+}
+
+impl<'ctx> Session<'ctx> {
+    /// With a `debug_file`, the compiled code has debug info saying it comes from that file.
+    pub fn new(
+        context: &'ctx Context,
+        opt_level: OptLevel,
+        debug_file: Option<&str>,
+    ) -> Result<Self, String> {
+        Ok(Session {
+            codegen: CodegenContext::new(context, opt_level, debug_file)?,
+            operators: Operators::default(),
+            target_machine: None,
+            prelude: Vec::new(),
+            host_functions: HashMap::new(),
+        })
+    }
+
+    /// A session that compiles a whole program for `target`, to be written out with
+    /// `write_object`.
+    pub fn ahead_of_time(
+        context: &'ctx Context,
+        target: &Target,
+        opt_level: OptLevel,
+        debug_file: Option<&str>,
+    ) -> Result<Self, String> {
+        let target_machine = target.create_machine(opt_level)?;
+
+        Ok(Session {
+            codegen: CodegenContext::ahead_of_time(context, opt_level, &target_machine, debug_file),
+            operators: Operators::default(),
+            target_machine: Some(target_machine),
+            prelude: Vec::new(),
+            host_functions: HashMap::new(),
+        })
+    }
+
+    pub fn opt_level(&self) -> OptLevel {
+        self.codegen.opt_level()
+    }
+
+    pub fn set_opt_level(&mut self, opt_level: OptLevel) {
+        self.codegen.set_opt_level(opt_level);
+    }
+
+    /// Compile the prelude of common functions. A program can define or declare functions of the
+    /// same name to replace them, with any number of arguments until it calls them. The prelude
+    /// can only fail to compile when it conflicts with functions registered before it.
+    pub fn load_prelude(&mut self) -> Result<(), Error> {
+        let mut diagnostics = Diagnostics::default();
+        // the debug info is only about the program's own source
+        self.codegen.set_source_debug_info(false);
+        for node in self.parse(PRELUDE, &mut diagnostics) {
+            let name = match &node {
+                AstNode::Function(function) => function.prototype.name.clone(),
+                AstNode::Prototype(prototype) => prototype.name.clone(),
+            };
+            let result = match node {
+                // there's no replacing a definition once compiled ahead of time, so that waits
+                // until it's known which ones the program leaves to the prelude
+                AstNode::Function(function) if self.target_machine.is_some() => {
+                    let prototype = self.codegen.codegen_prototype(function.prototype.clone());
+                    self.prelude.push(function);
+                    prototype
+                }
+                AstNode::Function(function) => self.codegen.codegen_function(function),
+                AstNode::Prototype(prototype) => self.codegen.codegen_prototype(prototype),
+            };
+            match result {
+                Ok(_) => self.codegen.add_to_prelude(&name),
+                Err(err) => diagnostics.push(err),
+            }
+        }
+        self.codegen.set_source_debug_info(true);
+
+        if diagnostics.has_errors() {
+            return Err(Error::Source {
+                source: PRELUDE.to_string(),
+                diagnostics,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Make `function` callable from Kaleidoscope code as `name`, as if declared with `extern`.
+    /// This replaces any earlier function of the same name, which has to take as many arguments.
+    /// Only a session running in the JIT can call back into Rust.
+    pub fn register_fn<Args, F: HostFn<Args>>(
+        &mut self,
+        name: &str,
+        function: F,
+    ) -> Result<(), String> {
+        let function = Box::new(function);
+        let data = &*function as *const F as usize;
+        self.codegen
+            .define_host_function(name, F::ARITY, F::trampoline(), data)?;
+        self.host_functions.insert(name.to_string(), function);
+
+        Ok(())
+    }
+
+    /// Make the functions of the shared library at `path` callable once declared with `extern`.
+    pub fn load_library(&self, path: &str) -> Result<(), String> {
+        self.codegen.load_library(path)
+    }
+
+    pub fn parse(&mut self, source: &str, diagnostics: &mut Diagnostics) -> Vec<AstNode> {
+        let token_reader = TokenReader::new(source.chars());
+        let mut parser = Parser::new(token_reader).with_operators(mem::take(&mut self.operators));
+
+        let (nodes, errors) = parser.parse_program();
+        for err in errors {
+            diagnostics.push(err);
+        }
+
+        self.operators = parser.into_operators();
+        nodes
+    }
+
+    /// Compile a definition or declaration, or evaluate a top-level expression, which only a
+    /// session running in the JIT can do.
+    pub fn run(&mut self, node: AstNode) -> Result<Output, CodegenError> {
+        match node {
+            AstNode::Function(function) if function.is_anonymous() => {
+                self.codegen.evaluate(function).map(Output::Evaluated)
+            }
+            node => self
+                .compile(node)
+                .map(|function| Output::Compiled(function.to_ir())),
+        }
+    }
+
+    // a definition or declaration
+    fn compile(&mut self, node: AstNode) -> Result<Function<'_>, CodegenError> {
+        let prototype = match &node {
+            AstNode::Function(function) => &function.prototype,
+            AstNode::Prototype(prototype) => prototype,
+        };
+        let forgotten = self.codegen.take_from_prelude(prototype)?;
+        self.prelude
+            .retain(|function| !forgotten.contains(&function.prototype.name));
+
+        match node {
+            AstNode::Function(function) => self.codegen.codegen_function(function),
+            AstNode::Prototype(prototype) => self.codegen.codegen_prototype(prototype),
+        }
+    }
+
+    /// Compile the definitions and declarations in `source` and evaluate its top-level
+    /// expressions, in order, returning the value of the last expression.
+    pub fn eval(&mut self, source: &str) -> Result<f64, Error> {
+        let mut diagnostics = Diagnostics::default();
+        let nodes = self.parse(source, &mut diagnostics);
+
+        let mut value = None;
+        if !diagnostics.has_errors() {
+            for node in nodes {
+                let result = match node {
+                    AstNode::Function(function) if function.is_anonymous() => self
+                        .codegen
+                        .evaluate(function)
+                        .map(|result| value = Some(result)),
+                    node => self.compile(node).map(|_| ()),
+                };
+                if let Err(err) = result {
+                    diagnostics.push(err);
+                }
+            }
+        }
+
+        if diagnostics.has_errors() {
+            return Err(Error::Source {
+                source: source.to_string(),
+                diagnostics,
+            });
+        }
+
+        value.ok_or(Error::NoExpression)
+    }
+
+    /// Compile the definitions and declarations in `source`, which mustn't have any top-level
+    /// expressions.
+    pub fn define(&mut self, source: &str) -> Result<(), Error> {
+        let mut diagnostics = Diagnostics::default();
+        let nodes = self.parse(source, &mut diagnostics);
+
+        if !diagnostics.has_errors() {
+            for node in nodes {
+                let result = match node {
+                    AstNode::Function(function) if function.is_anonymous() => {
+                        diagnostics.push(
+                            Diagnostic::error(
+                                "E0300",
+                                "expected a definition or declaration".to_string(),
+                                function.span,
+                            )
+                            .with_label("top-level expression".to_string())
+                            .with_note("use `eval` to evaluate expressions".to_string()),
+                        );
+                        continue;
+                    }
+                    node => self.compile(node),
+                };
+                if let Err(err) = result {
+                    diagnostics.push(err);
+                }
+            }
+        }
+
+        if diagnostics.has_errors() {
+            return Err(Error::Source {
+                source: source.to_string(),
+                diagnostics,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Call a function defined or declared so far, which takes at most 6 arguments.
+    pub fn call(&mut self, name: &str, args: &[f64]) -> Result<f64, Error> {
+        self.codegen.call(name, args).map_err(Error::Call)
+    }
+
+    /// Check and optimize the whole program, before writing it out with the `write_*` methods.
+    pub fn finish(&mut self) -> Result<(), String> {
+        self.codegen.set_source_debug_info(false);
+        let result = self.compile_called_prelude();
+        self.codegen.set_source_debug_info(true);
+        result?;
+
+        self.codegen.finish()
+    }
+
+    // compile the functions of the prelude that the program calls and doesn't define, and those
+    // they call in turn. They're kept out of the symbols of the object, where they could take the
+    // place of functions of the same name in the libraries it's linked with, like the C library's
+    // `abs`
+    fn compile_called_prelude(&mut self) -> Result<(), String> {
+        loop {
+            let (called, rest): (Vec<_>, Vec<_>) = mem::take(&mut self.prelude)
+                .into_iter()
+                .filter(|function| {
+                    self.codegen
+                        .defined_arity(&function.prototype.name)
+                        .is_none()
+                })
+                .partition(|function| self.codegen.is_called(&function.prototype.name));
+            if called.is_empty() {
+                for function in rest {
+                    self.codegen.remove_unused(&function.prototype.name);
+                }
+                return Ok(());
+            }
+            self.prelude = rest;
+
+            for function in called {
+                let function = self
+                    .codegen
+                    .codegen_function(function)
+                    .map_err(|err| format!("couldn't compile the prelude: {}", err))?;
+                function.set_internal();
+            }
+        }
+    }
+
+    pub fn write_object(&self, path: &str) -> Result<(), String> {
+        match &self.target_machine {
+            Some(target_machine) => self.codegen.write_object(target_machine, path),
+            None => Err("only a session compiling ahead of time can write objects".to_string()),
+        }
+    }
+
+    pub fn write_ir(&self, path: &str) -> Result<(), String> {
+        self.codegen.write_ir(path)
+    }
+
+    pub fn write_bitcode(&self, path: &str) -> Result<(), String> {
+        self.codegen.write_bitcode(path)
+    }
+
+    /// Make sure `name` can be called by the `main` generated when linking.
+    pub fn check_entry(&self, name: &str) -> Result<(), String> {
+        if name == "main" {
+            return Err(
+                "the entry function can't be called `main`, the generated `main` \
+                 calls it"
+                    .to_string(),
+            );
+        }
+
+        match self.codegen.defined_arity(name) {
+            None => Err(format!(
+                "there's no definition of the entry function `{}`",
+                name
+            )),
+            Some(0) => Ok(()),
+            Some(arity) => Err(format!(
+                "the entry function `{}` must take no arguments, but takes {}",
+                name, arity
+            )),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs;
+
+use kaleidoscope::{Context, OptLevel, Session, Target};
+
+// the IR of a program compiled ahead of time with the prelude, written to `name`
+fn compile(name: &str, source: &str) -> String {
+    let context = Context::new();
+    let mut session =
+        Session::ahead_of_time(&context, &Target::default(), OptLevel::O0, None).unwrap();
+    session.load_prelude().unwrap();
+    session.define(source).unwrap();
+    session.finish().unwrap();
+
+    let path = format!("{}/{}.ll", env!("CARGO_TARGET_TMPDIR"), name);
+    session.write_ir(&path).unwrap();
+    let ir = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    ir
+}
+
+#[test]
+fn only_called_functions_are_compiled_ahead_of_time() {
+    let ir = compile("called", "def run() abs(-2) + clamp(5, 0, 1);");
+
+    assert!(ir.contains("define internal double @abs("));
+    assert!(ir.contains("define internal double @clamp("));
+    // called by `clamp`
+    assert!(ir.contains("define internal double @min("));
+    assert!(ir.contains("define internal double @max("));
+    assert!(!ir.contains("@sign("));
+    assert!(!ir.contains("@fact("));
+}
+
+#[test]
+fn definitions_replace_prelude_functions_of_another_arity_ahead_of_time() {
+    let ir = compile(
+        "arity",
+        "def max(a b c) if a > b then a else if b > c then b else c;\n\
+         extern square(x y);\n\
+         def run() max(1, 2, 3) + square(2, 3) + min(1, 2);",
+    );
+
+    assert!(ir.contains("define double @max(double %a, double %b, double %c)"));
+    assert!(ir.contains("declare double @square(double, double)"));
+    assert!(ir.contains("define internal double @min("));
+}
+
+#[test]
+fn definitions_replace_prelude_functions_of_another_arity_in_the_jit() {
+    let context = Context::new();
+    let mut session = Session::new(&context, OptLevel::O2, None).unwrap();
+    session.load_prelude().unwrap();
+
+    session
+        .define(
+            "def max(a b c) if a > b then (if a > c then a else c) else if b > c then b else c;",
+        )
+        .unwrap();
+    assert_eq!(session.eval("max(1, 5, 3);").unwrap(), 5.0);
+    assert_eq!(session.eval("min(1, 5);").unwrap(), 1.0);
+    // it called the `max` of the prelude
+    assert!(session.eval("clamp(5, 0, 1);").is_err());
+}
+
+#[test]
+fn prelude_functions_the_program_calls_keep_their_arity() {
+    let context = Context::new();
+    let mut session = Session::new(&context, OptLevel::O0, None).unwrap();
+    session.load_prelude().unwrap();
+
+    session.define("def larger(a b) max(a, b);").unwrap();
+    assert!(session.define("def max(a b c) a;").is_err());
+    assert_eq!(session.eval("larger(2, 3);").unwrap(), 3.0);
+}
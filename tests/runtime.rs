@@ -0,0 +1,56 @@
+use std::fs;
+
+use kaleidoscope::{Context, OptLevel, Session, Target};
+
+// the IR of a program compiled ahead of time with the prelude, written to `name`
+fn compile(name: &str, source: &str) -> String {
+    let context = Context::new();
+    let mut session =
+        Session::ahead_of_time(&context, &Target::default(), OptLevel::O0, None).unwrap();
+    session.load_prelude().unwrap();
+    session.define(source).unwrap();
+    session.finish().unwrap();
+
+    let path = format!("{}/{}.ll", env!("CARGO_TARGET_TMPDIR"), name);
+    session.write_ir(&path).unwrap();
+    let ir = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    ir
+}
+
+#[test]
+fn builtins_call_the_runtime_archive() {
+    let ir = compile("builtins", "def run() printd(random() + clock());");
+
+    assert!(ir.contains("declare double @kaleidoscope_random()"));
+    assert!(ir.contains("declare double @kaleidoscope_clock()"));
+    assert!(ir.contains("call double @kaleidoscope_printd("));
+    assert!(!ir.contains("@clock"));
+}
+
+#[test]
+fn definitions_replace_builtins() {
+    let ir = compile("replaced", "def clock() 42; def run() clock();");
+
+    assert!(ir.contains("define double @clock()"));
+    assert!(!ir.contains("kaleidoscope_clock"));
+}
+
+#[test]
+fn definitions_replace_builtins_in_the_jit() {
+    let context = Context::new();
+    let mut session = Session::new(&context, OptLevel::O0, None).unwrap();
+    session.load_prelude().unwrap();
+
+    session.define("def roll() random();").unwrap();
+    let value = session.eval("roll();").unwrap();
+    assert!((0.0..1.0).contains(&value));
+
+    session.define("def random() 7;").unwrap();
+    assert_eq!(session.eval("random();").unwrap(), 7.0);
+    assert_eq!(session.eval("roll();").unwrap(), 7.0);
+    assert_eq!(session.call("random", &[]).unwrap(), 7.0);
+    // what isn't defined is still found outside the JIT
+    assert_eq!(session.call("sqrt", &[4.0]).unwrap(), 2.0);
+}
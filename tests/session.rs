@@ -0,0 +1,111 @@
+use kaleidoscope::{Context, OptLevel, Session, Target};
+
+#[test]
+fn failed_definition_keeps_the_declaration_of_compiled_calls() {
+    let context = Context::new();
+    let mut session =
+        Session::ahead_of_time(&context, &Target::default(), OptLevel::O0, None).unwrap();
+    session.load_prelude().unwrap();
+
+    assert!(session
+        .define("extern f(x); def g(x) f(x); def f(x) y;")
+        .is_err());
+    // the prelude's `min` is left to compile in `finish`
+    assert!(session
+        .define("def h(x) min(x, 1); def min(a b) c;")
+        .is_err());
+    session.define("def f(x) x + 1;").unwrap();
+    session.finish().unwrap();
+}
+
+fn redefinitions_reach_callers(opt_level: OptLevel) {
+    let context = Context::new();
+    let mut session = Session::new(&context, opt_level, None).unwrap();
+
+    session
+        .define("def f(x) x + 1; def g(x) f(x) * 2; def h(x) g(x) + 100;")
+        .unwrap();
+    assert_eq!(session.eval("g(1);").unwrap(), 4.0);
+    assert_eq!(session.eval("h(1);").unwrap(), 104.0);
+
+    session.define("def f(x) x + 10;").unwrap();
+    assert_eq!(session.eval("g(1);").unwrap(), 22.0);
+    // through `g`, which was compiled again
+    assert_eq!(session.eval("h(1);").unwrap(), 122.0);
+    assert_eq!(session.call("g", &[2.0]).unwrap(), 24.0);
+}
+
+#[test]
+fn redefinitions_reach_callers_without_optimizations() {
+    redefinitions_reach_callers(OptLevel::O0);
+}
+
+#[test]
+fn redefinitions_reach_callers_that_inlined_them() {
+    redefinitions_reach_callers(OptLevel::O2);
+}
+
+#[test]
+fn redefinitions_replace_registered_functions() {
+    let context = Context::new();
+    let mut session = Session::new(&context, OptLevel::O1, None).unwrap();
+    session.register_fn("f", |x: f64| x * 3.0).unwrap();
+
+    session.define("def g(x) f(x) + 1;").unwrap();
+    assert_eq!(session.eval("g(2);").unwrap(), 7.0);
+    session.define("def f(x) x;").unwrap();
+    assert_eq!(session.eval("g(2);").unwrap(), 3.0);
+}
+
+#[test]
+fn failed_redefinition_keeps_the_previous_one() {
+    let context = Context::new();
+    let mut session = Session::new(&context, OptLevel::O1, None).unwrap();
+
+    session.define("def f(x) x + 1; def g(x) f(x);").unwrap();
+    assert!(session.define("def f(x) y;").is_err());
+    assert_eq!(session.eval("g(1);").unwrap(), 2.0);
+}
+
+#[test]
+fn conflicting_arity_is_an_error() {
+    let context = Context::new();
+    let mut session = Session::new(&context, OptLevel::O1, None).unwrap();
+
+    session.define("def f(x) x;").unwrap();
+    let err = session.define("def f(x y) x + y;").unwrap_err();
+    assert!(err.to_string().contains("error[E0204]"), "{}", err);
+    let err = session.define("extern f(x y);").unwrap_err();
+    assert!(err.to_string().contains("error[E0204]"), "{}", err);
+    assert_eq!(session.eval("f(5);").unwrap(), 5.0);
+}
+
+#[test]
+fn definitions_fill_in_externs() {
+    let context = Context::new();
+    let mut session = Session::new(&context, OptLevel::O1, None).unwrap();
+
+    session
+        .define("extern twice(x); def quad(x) twice(twice(x));")
+        .unwrap();
+    session.define("def twice(x) x * 2;").unwrap();
+    assert_eq!(session.eval("quad(3);").unwrap(), 12.0);
+
+    let mut session =
+        Session::ahead_of_time(&context, &Target::default(), OptLevel::O1, None).unwrap();
+    session
+        .define("extern twice(x); def quad(x) twice(twice(x)); def twice(x) x * 2;")
+        .unwrap();
+    session.finish().unwrap();
+}
+
+#[test]
+fn functions_are_defined_once_ahead_of_time() {
+    let context = Context::new();
+    let mut session =
+        Session::ahead_of_time(&context, &Target::default(), OptLevel::O1, None).unwrap();
+
+    session.define("def f(x) x;").unwrap();
+    let err = session.define("def f(x) x + 1;").unwrap_err();
+    assert!(err.to_string().contains("error[E0205]"), "{}", err);
+}
@@ -0,0 +1,66 @@
+use std::fs;
+
+use kaleidoscope::{Context, OptLevel, Session, Target};
+
+// the `e_machine` field of the ELF header of an object compiled for `triple`
+fn elf_machine(triple: &str) -> u16 {
+    let context = Context::new();
+    let target = Target {
+        triple: Some(triple.to_string()),
+        ..Target::default()
+    };
+    let mut session = Session::ahead_of_time(&context, &target, OptLevel::O2, None).unwrap();
+    session
+        .define("def twice(x) x * 2; def run() twice(21);")
+        .unwrap();
+    session.finish().unwrap();
+
+    let path = format!("{}/{}.o", env!("CARGO_TARGET_TMPDIR"), triple);
+    session.write_object(&path).unwrap();
+    let object = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(&object[..4], b"\x7fELF");
+    u16::from_le_bytes([object[18], object[19]])
+}
+
+#[test]
+fn compiles_for_x86_64() {
+    assert_eq!(elf_machine("x86_64-unknown-linux-gnu"), 0x3e);
+}
+
+#[test]
+fn compiles_for_aarch64() {
+    assert_eq!(elf_machine("aarch64-unknown-linux-gnu"), 0xb7);
+}
+
+#[test]
+fn compiles_for_riscv64() {
+    assert_eq!(elf_machine("riscv64-unknown-linux-gnu"), 0xf3);
+}
+
+#[test]
+fn only_the_host_is_the_host() {
+    assert!(Target::default().is_host());
+
+    let target = |triple: &str| Target {
+        triple: Some(triple.to_string()),
+        ..Target::default()
+    };
+    assert_eq!(
+        target("aarch64-unknown-linux-gnu").is_host(),
+        cfg!(all(
+            target_arch = "aarch64",
+            target_os = "linux",
+            target_env = "gnu"
+        ))
+    );
+    assert_eq!(
+        target("x86_64-unknown-linux-gnu").is_host(),
+        cfg!(all(
+            target_arch = "x86_64",
+            target_os = "linux",
+            target_env = "gnu"
+        ))
+    );
+}